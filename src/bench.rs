@@ -0,0 +1,60 @@
+use crate::cl::{Command, Timing};
+use linked_hash_map::LinkedHashMap;
+
+/// Durations of the profiled commands of a benchmark, in nanoseconds, grouped
+/// by the job that enqueued them and what they worked on
+#[derive(Default)]
+pub struct Samples(LinkedHashMap<String, Vec<u64>>);
+
+impl Samples {
+    pub fn add(&mut self, job: &str, timings: Vec<Timing>) {
+        for timing in timings {
+            let label = match timing.command {
+                Command::Kernel => format!("{}: {}", job, timing.name),
+                Command::Read => format!("{}: read {}", job, timing.name),
+                Command::Write => format!("{}: write {}", job, timing.name),
+            };
+            self.0.entry(label).or_default().push(timing.duration());
+        }
+    }
+
+    /// Prints min/median/mean/stddev of every group, in milliseconds
+    pub fn report(&self) {
+        let width = self.0.keys().map(|k| k.len()).max().unwrap_or(0);
+        println!(
+            "{:<width$} {:>6} {:>10} {:>10} {:>10} {:>10}",
+            "",
+            "count",
+            "min",
+            "median",
+            "mean",
+            "stddev",
+            width = width
+        );
+        for (label, samples) in self.0.iter() {
+            let mut ms = samples
+                .iter()
+                .map(|&ns| ns as f64 / 1e6)
+                .collect::<Vec<_>>();
+            ms.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let n = ms.len() as f64;
+            let median = if ms.len() % 2 == 0 {
+                (ms[ms.len() / 2 - 1] + ms[ms.len() / 2]) / 2.0
+            } else {
+                ms[ms.len() / 2]
+            };
+            let mean = ms.iter().sum::<f64>() / n;
+            let stddev = (ms.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+            println!(
+                "{:<width$} {:>6} {:>8.3}ms {:>8.3}ms {:>8.3}ms {:>8.3}ms",
+                label,
+                ms.len(),
+                ms[0],
+                median,
+                mean,
+                stddev,
+                width = width
+            );
+        }
+    }
+}
@@ -1,57 +1,426 @@
-use crate::conf::{Arg, BufferType, Computable, Environment};
-use rust_gpu_tools::opencl as cl;
+use crate::conf::{Arg, BufferType, Computable, Device, Environment, Value};
+use crate::error::{ClmanError, ClmanResult};
+use ocl::enums::{
+    DeviceInfo, DeviceInfoResult, KernelWorkGroupInfo, KernelWorkGroupInfoResult, ProfilingInfo,
+};
+use ocl::{prm, SpatialDims};
 use std::collections::HashMap;
+use std::convert::TryInto;
+
+fn compute_vector<T, const N: usize>(values: &[Value<T>], env: &Environment) -> ClmanResult<[T; N]>
+where
+    T: Default + Clone + std::str::FromStr + std::fmt::Debug,
+    <T as std::str::FromStr>::Err: std::fmt::Debug,
+{
+    if values.len() != N {
+        return Err(ClmanError::Job(format!(
+            "Vector argument must have {} components, got {}",
+            N,
+            values.len()
+        )));
+    }
+    let values = values.iter().map(|v| v.compute(env)).collect::<Vec<_>>();
+    Ok(values.try_into().unwrap())
+}
+
+/// Global size, and optional local size and global offset of a kernel launch,
+/// each with 1 to 3 dimensions
+pub struct NDRange {
+    pub global: Vec<usize>,
+    pub local: Option<Vec<usize>>,
+    pub offset: Option<Vec<usize>>,
+}
+
+impl NDRange {
+    pub fn check(&self) -> ClmanResult<()> {
+        let dims = self.global.len();
+        if dims == 0 || dims > 3 {
+            return Err(ClmanError::WorkSize(format!(
+                "Global work size must have 1 to 3 dimensions, got {}",
+                dims
+            )));
+        }
+        if self.global.contains(&0) {
+            return Err(ClmanError::WorkSize(format!(
+                "Global work size {:?} has an empty dimension",
+                self.global
+            )));
+        }
+        for (name, other) in [
+            ("Local work size", &self.local),
+            ("Global work offset", &self.offset),
+        ]
+        .iter()
+        {
+            if let Some(other) = other {
+                if other.len() != dims {
+                    return Err(ClmanError::WorkSize(format!(
+                        "{} has {} dimensions, global work size has {}",
+                        name,
+                        other.len(),
+                        dims
+                    )));
+                }
+            }
+        }
+        if let Some(local) = &self.local {
+            if self
+                .global
+                .iter()
+                .zip(local.iter())
+                .any(|(g, l)| *l == 0 || g % l != 0)
+            {
+                return Err(ClmanError::WorkSize(format!(
+                    "Global work size {:?} is not a multiple of local work size {:?}",
+                    self.global, local
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn spatial_dims(dims: &[usize]) -> SpatialDims {
+    match *dims {
+        [x] => SpatialDims::One(x),
+        [x, y] => SpatialDims::Two(x, y),
+        [x, y, z] => SpatialDims::Three(x, y, z),
+        _ => unreachable!(),
+    }
+}
+
+/// Every OpenCL device, with the platform it belongs to. Devices are numbered
+/// by their position in this list.
+pub fn devices() -> ClmanResult<Vec<(ocl::Platform, ocl::Device)>> {
+    let mut ret = Vec::new();
+    for platform in ocl::Platform::list()? {
+        for device in ocl::Device::list_all(platform)? {
+            ret.push((platform, device));
+        }
+    }
+    Ok(ret)
+}
+
+pub fn device_type(device: &ocl::Device) -> ClmanResult<&'static str> {
+    Ok(match device.info(DeviceInfo::Type)? {
+        DeviceInfoResult::Type(t) if t.contains(ocl::DeviceType::GPU) => "gpu",
+        DeviceInfoResult::Type(t) if t.contains(ocl::DeviceType::CPU) => "cpu",
+        DeviceInfoResult::Type(t) if t.contains(ocl::DeviceType::ACCELERATOR) => "accelerator",
+        _ => "custom",
+    })
+}
+
+/// Finds a device of `devices()`. Without a preference, the first device of
+/// the default platform is used.
+pub fn find_device(preference: Option<&Device>) -> ClmanResult<(ocl::Platform, ocl::Device)> {
+    match preference {
+        None => {
+            let platform = ocl::Platform::default();
+            Ok((platform, ocl::Device::first(platform)?))
+        }
+        Some(Device::Index(index)) => {
+            let devices = devices()?;
+            devices.get(*index).cloned().ok_or_else(|| {
+                ClmanError::Device(format!(
+                    "No device {}, there are {} devices",
+                    index,
+                    devices.len()
+                ))
+            })
+        }
+        Some(Device::Name(name)) => {
+            let devices = devices()?;
+            let lowercase = name.to_lowercase();
+            for (platform, device) in devices.iter() {
+                if device_type(device)? == lowercase
+                    || device.name()?.to_lowercase().contains(&lowercase)
+                {
+                    return Ok((*platform, *device));
+                }
+            }
+            Err(ClmanError::Device(format!("No device matches {:?}", name)))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Kernel,
+    Read,
+    Write,
+}
+
+/// Device timestamps of a profiled command, in nanoseconds
+#[derive(Debug, Clone)]
+pub struct Timing {
+    pub command: Command,
+    /// Kernel or buffer the command worked on
+    pub name: String,
+    pub queued: u64,
+    pub submit: u64,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Timing {
+    pub fn duration(&self) -> u64 {
+        self.end - self.start
+    }
+}
 
 struct TypedBuffer {
-    pub buffer: cl::Buffer<u8>,
+    pub buffer: ocl::Buffer<u8>,
     pub buffer_type: BufferType,
     pub length: usize,
 }
 
 impl TypedBuffer {
-    pub fn new(
-        program: &cl::Program,
-        buffer_type: BufferType,
-        length: usize,
-    ) -> cl::GPUResult<Self> {
+    pub fn new(queue: &ocl::Queue, buffer_type: BufferType, length: usize) -> ClmanResult<Self> {
         Ok(Self {
             buffer_type,
-            buffer: program.create_buffer::<u8>(buffer_type.size_of() * length)?,
+            buffer: ocl::Buffer::<u8>::builder()
+                .queue(queue.clone())
+                .flags(ocl::MemFlags::new().read_write())
+                .len(buffer_type.size_of() * length)
+                .build()?,
             length,
         })
     }
 }
 
 pub struct GPU {
-    program: cl::Program,
+    device: ocl::Device,
+    program: ocl::Program,
+    queue: ocl::Queue,
     buffers: HashMap<String, TypedBuffer>,
+    /// Timings of the commands enqueued so far, when profiling
+    timings: Option<Vec<Timing>>,
+    source: String,
+    build_options: String,
+    /// Devices sharded run jobs have been spread on, by preference
+    shards: HashMap<Device, GPU>,
 }
 
 impl GPU {
-    pub fn new(source: String) -> cl::GPUResult<Self> {
-        let dev = cl::Device::all()?[0].clone();
+    pub fn new(
+        source: String,
+        build_options: String,
+        device: Option<&Device>,
+        profile: bool,
+    ) -> ClmanResult<Self> {
+        let (platform, device) = find_device(device)?;
+        let context = ocl::Context::builder()
+            .platform(platform)
+            .devices(device)
+            .build()?;
         Ok(GPU {
-            program: cl::Program::from_opencl(dev, &source)?,
+            device,
+            program: ocl::Program::builder()
+                .src(source.clone())
+                .cmplr_opt(build_options.clone())
+                .devices(device)
+                .build(&context)?,
+            queue: ocl::Queue::new(
+                &context,
+                device,
+                if profile {
+                    Some(ocl::CommandQueueProperties::new().profiling())
+                } else {
+                    None
+                },
+            )?,
             buffers: HashMap::new(),
+            timings: if profile { Some(Vec::new()) } else { None },
+            source,
+            build_options,
+            shards: HashMap::new(),
         })
     }
 
+    /// Removes and returns the timings recorded so far
+    pub fn take_timings(&mut self) -> Vec<Timing> {
+        self.timings
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn record(&mut self, command: Command, name: String, event: &ocl::Event) -> ClmanResult<()> {
+        if let Some(timings) = &mut self.timings {
+            event.wait_for()?;
+            let time = |info| -> ClmanResult<u64> { Ok(event.profiling_info(info)?.time()?) };
+            timings.push(Timing {
+                command,
+                name,
+                queued: time(ProfilingInfo::Queued)?,
+                submit: time(ProfilingInfo::Submit)?,
+                start: time(ProfilingInfo::Start)?,
+                end: time(ProfilingInfo::End)?,
+            });
+        }
+        Ok(())
+    }
+
     pub fn create_buffer(
         &mut self,
         name: String,
         buffer_type: BufferType,
         length: usize,
-    ) -> cl::GPUResult<()> {
+    ) -> ClmanResult<()> {
         self.buffers
-            .insert(name, TypedBuffer::new(&self.program, buffer_type, length)?);
+            .insert(name, TypedBuffer::new(&self.queue, buffer_type, length)?);
         Ok(())
     }
 
-    pub fn read_buffer<T: Clone>(&self, name: String) -> cl::GPUResult<Vec<T>> {
-        let buff = self.buffers.get(&name).unwrap();
-        let mut as_u8 = vec![0u8; buff.buffer.length()];
-        buff.buffer.read_into(&mut as_u8)?;
-        Ok(unsafe { std::slice::from_raw_parts(as_u8.as_ptr() as *const T, buff.length).to_vec() })
+    fn buffer(&self, name: &str) -> ClmanResult<&TypedBuffer> {
+        self.buffers
+            .get(name)
+            .ok_or_else(|| ClmanError::Buffer(format!("No buffer named {}", name)))
+    }
+
+    pub fn buffer_type(&self, name: String) -> ClmanResult<BufferType> {
+        Ok(self.buffer(&name)?.buffer_type)
+    }
+
+    pub fn read_buffer<T: Clone>(&mut self, name: String) -> ClmanResult<Vec<T>> {
+        let buff = self.buffer(&name)?;
+        let mut as_u8 = vec![0u8; buff.buffer.len()];
+        let mut event = ocl::Event::empty();
+        buff.buffer.read(&mut as_u8).enew(&mut event).enq()?;
+        self.record(Command::Read, name, &event)?;
+        let length = as_u8.len() / std::mem::size_of::<T>();
+        Ok(unsafe { std::slice::from_raw_parts(as_u8.as_ptr() as *const T, length).to_vec() })
+    }
+
+    pub fn write_buffer(&mut self, name: String, data: &[u8]) -> ClmanResult<()> {
+        let buff = self.buffer(&name)?;
+        let expected = buff.buffer_type.size_of() * buff.length;
+        if data.len() != expected {
+            return Err(ClmanError::Size {
+                name,
+                expected,
+                actual: data.len(),
+            });
+        }
+        let mut event = ocl::Event::empty();
+        buff.buffer.write(data).enew(&mut event).enq()?;
+        self.record(Command::Write, name, &event)?;
+        Ok(())
+    }
+
+    /// Exchanges the memory behind two buffers of the same type and length
+    pub fn swap_buffers(&mut self, a: String, b: String) -> ClmanResult<()> {
+        let (buff_a, buff_b) = (self.buffer(&a)?, self.buffer(&b)?);
+        if buff_a.buffer_type != buff_b.buffer_type || buff_a.length != buff_b.length {
+            return Err(ClmanError::Buffer(format!(
+                "Cannot swap {} ({} x {:?}) with {} ({} x {:?})",
+                a, buff_a.length, buff_a.buffer_type, b, buff_b.length, buff_b.buffer_type
+            )));
+        }
+        if a == b {
+            return Ok(());
+        }
+        let buff_a = self.buffers.remove(&a).unwrap();
+        let buff_b = self.buffers.remove(&b).unwrap();
+        self.buffers.insert(a, buff_b);
+        self.buffers.insert(b, buff_a);
+        Ok(())
+    }
+
+    /// Copies `count` elements (Or as many as `from` has after `src_offset`)
+    /// on the device
+    pub fn copy_buffer(
+        &mut self,
+        from: String,
+        to: String,
+        src_offset: usize,
+        dst_offset: usize,
+        count: Option<usize>,
+    ) -> ClmanResult<()> {
+        let (src, dst) = (self.buffer(&from)?, self.buffer(&to)?);
+        if src.buffer_type != dst.buffer_type {
+            return Err(ClmanError::Buffer(format!(
+                "Cannot copy {} ({:?}) to {} ({:?})",
+                from, src.buffer_type, to, dst.buffer_type
+            )));
+        }
+        let count = count.unwrap_or_else(|| src.length.saturating_sub(src_offset));
+        if src_offset + count > src.length || dst_offset + count > dst.length {
+            return Err(ClmanError::Buffer(format!(
+                "Cannot copy {} elements from {}[{}..] ({} elements) to {}[{}..] ({} elements)",
+                count, from, src_offset, src.length, to, dst_offset, dst.length
+            )));
+        }
+        let size = src.buffer_type.size_of();
+        src.buffer
+            .copy(&dst.buffer, Some(dst_offset * size), Some(count * size))
+            .offset(src_offset * size)
+            .enq()?;
+        Ok(())
+    }
+
+    /// Sets every element of a buffer to `pattern`, on the device
+    pub fn fill_buffer(&mut self, name: String, pattern: &[u8]) -> ClmanResult<()> {
+        fn fill<T: ocl::OclPrm>(
+            queue: &ocl::Queue,
+            buffer: &ocl::Buffer<u8>,
+            pattern: &[u8],
+        ) -> ClmanResult<()> {
+            let pattern = unsafe { std::ptr::read_unaligned(pattern.as_ptr() as *const T) };
+            ocl::core::enqueue_fill_buffer(
+                queue.as_core(),
+                buffer.as_core(),
+                pattern,
+                0,
+                buffer.len() / std::mem::size_of::<T>(),
+                None::<&ocl::core::Event>,
+                None::<&mut ocl::core::Event>,
+                None,
+            )?;
+            Ok(())
+        }
+
+        let buff = self.buffer(&name)?;
+        assert_eq!(pattern.len(), buff.buffer_type.size_of());
+        // Element sizes are powers of two, from `char` up to `double16`
+        match pattern.len() {
+            1 => fill::<u8>(&self.queue, &buff.buffer, pattern),
+            2 => fill::<u16>(&self.queue, &buff.buffer, pattern),
+            4 => fill::<u32>(&self.queue, &buff.buffer, pattern),
+            8 => fill::<u64>(&self.queue, &buff.buffer, pattern),
+            16 => fill::<prm::Ulong2>(&self.queue, &buff.buffer, pattern),
+            32 => fill::<prm::Ulong4>(&self.queue, &buff.buffer, pattern),
+            64 => fill::<prm::Ulong8>(&self.queue, &buff.buffer, pattern),
+            128 => fill::<prm::Ulong16>(&self.queue, &buff.buffer, pattern),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Checks a local work size against the limits of the device and the
+    /// kernel
+    fn check_local_size(&self, kern: &ocl::Kernel, local: &[usize]) -> ClmanResult<()> {
+        if let DeviceInfoResult::MaxWorkItemSizes(max) =
+            self.device.info(DeviceInfo::MaxWorkItemSizes)?
+        {
+            if local.iter().zip(max.iter()).any(|(l, m)| l > m) {
+                return Err(ClmanError::WorkSize(format!(
+                    "Local work size {:?} exceeds the device's max work item sizes {:?}",
+                    local, max
+                )));
+            }
+        }
+        let max_wg_size = match kern.wg_info(self.device, KernelWorkGroupInfo::WorkGroupSize)? {
+            KernelWorkGroupInfoResult::WorkGroupSize(size) => size,
+            _ => self.device.max_wg_size()?,
+        };
+        let wg_size = local.iter().product::<usize>();
+        if wg_size > max_wg_size {
+            return Err(ClmanError::WorkSize(format!(
+                "Local work size {:?} ({} work-items) exceeds the max work-group size {}",
+                local, wg_size, max_wg_size
+            )));
+        }
+        Ok(())
     }
 
     pub fn run_kernel(
@@ -59,52 +428,370 @@ impl GPU {
         env: &Environment,
         name: String,
         args: Vec<Arg>,
-        global_work_size: usize,
-    ) -> cl::GPUResult<()> {
-        let mut kern = self
-            .program
-            .create_kernel(&name[..], global_work_size, None);
+        range: NDRange,
+    ) -> ClmanResult<()> {
+        range.check()?;
+        let mut kern = ocl::Kernel::builder();
+        kern.name(&name[..])
+            .program(&self.program)
+            .queue(self.queue.clone())
+            .global_work_size(spatial_dims(&range.global));
+        if let Some(local) = &range.local {
+            kern.local_work_size(spatial_dims(local));
+        }
+        if let Some(offset) = &range.offset {
+            kern.global_work_offset(spatial_dims(offset));
+        }
+        // Buffers are untyped bytes on the host side
+        unsafe {
+            kern.disable_arg_type_check();
+        }
         for arg in args {
             match arg {
                 Arg::Char(v) => {
-                    kern = kern.arg(v.compute(env));
+                    kern.arg(v.compute(env));
                 }
                 Arg::Uchar(v) => {
-                    kern = kern.arg(v.compute(env));
+                    kern.arg(v.compute(env));
                 }
                 Arg::Short(v) => {
-                    kern = kern.arg(v.compute(env));
+                    kern.arg(v.compute(env));
                 }
                 Arg::Ushort(v) => {
-                    kern = kern.arg(v.compute(env));
+                    kern.arg(v.compute(env));
                 }
                 Arg::Int(v) => {
-                    kern = kern.arg(v.compute(env));
+                    kern.arg(v.compute(env));
                 }
                 Arg::Uint(v) => {
-                    kern = kern.arg(v.compute(env));
+                    kern.arg(v.compute(env));
                 }
                 Arg::Long(v) => {
-                    kern = kern.arg(v.compute(env));
+                    kern.arg(v.compute(env));
                 }
                 Arg::Ulong(v) => {
-                    kern = kern.arg(v.compute(env));
+                    kern.arg(v.compute(env));
                 }
                 Arg::Float(v) => {
-                    kern = kern.arg(v.compute(env));
+                    kern.arg(v.compute(env));
                 }
                 Arg::Double(v) => {
-                    kern = kern.arg(v.compute(env));
+                    kern.arg(v.compute(env));
+                }
+                Arg::Char2(v) => {
+                    kern.arg(prm::Char2::from(compute_vector::<_, 2>(&v, env)?));
+                }
+                Arg::Char3(v) => {
+                    kern.arg(prm::Char3::from(compute_vector::<_, 3>(&v, env)?));
+                }
+                Arg::Char4(v) => {
+                    kern.arg(prm::Char4::from(compute_vector::<_, 4>(&v, env)?));
+                }
+                Arg::Char8(v) => {
+                    kern.arg(prm::Char8::from(compute_vector::<_, 8>(&v, env)?));
+                }
+                Arg::Char16(v) => {
+                    kern.arg(prm::Char16::from(compute_vector::<_, 16>(&v, env)?));
+                }
+                Arg::Uchar2(v) => {
+                    kern.arg(prm::Uchar2::from(compute_vector::<_, 2>(&v, env)?));
+                }
+                Arg::Uchar3(v) => {
+                    kern.arg(prm::Uchar3::from(compute_vector::<_, 3>(&v, env)?));
+                }
+                Arg::Uchar4(v) => {
+                    kern.arg(prm::Uchar4::from(compute_vector::<_, 4>(&v, env)?));
+                }
+                Arg::Uchar8(v) => {
+                    kern.arg(prm::Uchar8::from(compute_vector::<_, 8>(&v, env)?));
+                }
+                Arg::Uchar16(v) => {
+                    kern.arg(prm::Uchar16::from(compute_vector::<_, 16>(&v, env)?));
+                }
+                Arg::Short2(v) => {
+                    kern.arg(prm::Short2::from(compute_vector::<_, 2>(&v, env)?));
+                }
+                Arg::Short3(v) => {
+                    kern.arg(prm::Short3::from(compute_vector::<_, 3>(&v, env)?));
+                }
+                Arg::Short4(v) => {
+                    kern.arg(prm::Short4::from(compute_vector::<_, 4>(&v, env)?));
+                }
+                Arg::Short8(v) => {
+                    kern.arg(prm::Short8::from(compute_vector::<_, 8>(&v, env)?));
+                }
+                Arg::Short16(v) => {
+                    kern.arg(prm::Short16::from(compute_vector::<_, 16>(&v, env)?));
+                }
+                Arg::Ushort2(v) => {
+                    kern.arg(prm::Ushort2::from(compute_vector::<_, 2>(&v, env)?));
+                }
+                Arg::Ushort3(v) => {
+                    kern.arg(prm::Ushort3::from(compute_vector::<_, 3>(&v, env)?));
+                }
+                Arg::Ushort4(v) => {
+                    kern.arg(prm::Ushort4::from(compute_vector::<_, 4>(&v, env)?));
+                }
+                Arg::Ushort8(v) => {
+                    kern.arg(prm::Ushort8::from(compute_vector::<_, 8>(&v, env)?));
+                }
+                Arg::Ushort16(v) => {
+                    kern.arg(prm::Ushort16::from(compute_vector::<_, 16>(&v, env)?));
+                }
+                Arg::Int2(v) => {
+                    kern.arg(prm::Int2::from(compute_vector::<_, 2>(&v, env)?));
+                }
+                Arg::Int3(v) => {
+                    kern.arg(prm::Int3::from(compute_vector::<_, 3>(&v, env)?));
+                }
+                Arg::Int4(v) => {
+                    kern.arg(prm::Int4::from(compute_vector::<_, 4>(&v, env)?));
+                }
+                Arg::Int8(v) => {
+                    kern.arg(prm::Int8::from(compute_vector::<_, 8>(&v, env)?));
+                }
+                Arg::Int16(v) => {
+                    kern.arg(prm::Int16::from(compute_vector::<_, 16>(&v, env)?));
+                }
+                Arg::Uint2(v) => {
+                    kern.arg(prm::Uint2::from(compute_vector::<_, 2>(&v, env)?));
+                }
+                Arg::Uint3(v) => {
+                    kern.arg(prm::Uint3::from(compute_vector::<_, 3>(&v, env)?));
+                }
+                Arg::Uint4(v) => {
+                    kern.arg(prm::Uint4::from(compute_vector::<_, 4>(&v, env)?));
+                }
+                Arg::Uint8(v) => {
+                    // `prm::Uint8` is declared with `i8` components, pass the bits as `int8`
+                    let v = compute_vector::<u32, 8>(&v, env)?;
+                    kern.arg(prm::Int8::from(v.map(|c| c as i32)));
+                }
+                Arg::Uint16(v) => {
+                    kern.arg(prm::Uint16::from(compute_vector::<_, 16>(&v, env)?));
+                }
+                Arg::Long2(v) => {
+                    kern.arg(prm::Long2::from(compute_vector::<_, 2>(&v, env)?));
+                }
+                Arg::Long3(v) => {
+                    kern.arg(prm::Long3::from(compute_vector::<_, 3>(&v, env)?));
+                }
+                Arg::Long4(v) => {
+                    kern.arg(prm::Long4::from(compute_vector::<_, 4>(&v, env)?));
+                }
+                Arg::Long8(v) => {
+                    kern.arg(prm::Long8::from(compute_vector::<_, 8>(&v, env)?));
+                }
+                Arg::Long16(v) => {
+                    kern.arg(prm::Long16::from(compute_vector::<_, 16>(&v, env)?));
+                }
+                Arg::Ulong2(v) => {
+                    kern.arg(prm::Ulong2::from(compute_vector::<_, 2>(&v, env)?));
+                }
+                Arg::Ulong3(v) => {
+                    kern.arg(prm::Ulong3::from(compute_vector::<_, 3>(&v, env)?));
+                }
+                Arg::Ulong4(v) => {
+                    kern.arg(prm::Ulong4::from(compute_vector::<_, 4>(&v, env)?));
+                }
+                Arg::Ulong8(v) => {
+                    kern.arg(prm::Ulong8::from(compute_vector::<_, 8>(&v, env)?));
+                }
+                Arg::Ulong16(v) => {
+                    kern.arg(prm::Ulong16::from(compute_vector::<_, 16>(&v, env)?));
+                }
+                Arg::Float2(v) => {
+                    kern.arg(prm::Float2::from(compute_vector::<_, 2>(&v, env)?));
+                }
+                Arg::Float3(v) => {
+                    kern.arg(prm::Float3::from(compute_vector::<_, 3>(&v, env)?));
+                }
+                Arg::Float4(v) => {
+                    kern.arg(prm::Float4::from(compute_vector::<_, 4>(&v, env)?));
+                }
+                Arg::Float8(v) => {
+                    kern.arg(prm::Float8::from(compute_vector::<_, 8>(&v, env)?));
+                }
+                Arg::Float16(v) => {
+                    kern.arg(prm::Float16::from(compute_vector::<_, 16>(&v, env)?));
+                }
+                Arg::Double2(v) => {
+                    kern.arg(prm::Double2::from(compute_vector::<_, 2>(&v, env)?));
+                }
+                Arg::Double3(v) => {
+                    kern.arg(prm::Double3::from(compute_vector::<_, 3>(&v, env)?));
+                }
+                Arg::Double4(v) => {
+                    kern.arg(prm::Double4::from(compute_vector::<_, 4>(&v, env)?));
+                }
+                Arg::Double8(v) => {
+                    kern.arg(prm::Double8::from(compute_vector::<_, 8>(&v, env)?));
+                }
+                Arg::Double16(v) => {
+                    kern.arg(prm::Double16::from(compute_vector::<_, 16>(&v, env)?));
                 }
                 Arg::Buffer(name) => {
-                    let buff = self.buffers.get(&name.compute(env)).unwrap();
-                    kern = kern.arg(&buff.buffer);
+                    let buff = self.buffer(&name.compute(env))?;
+                    kern.arg(&buff.buffer);
+                }
+                Arg::Local { r#type, count } => {
+                    kern.arg_local::<u8>(r#type.size_of() * count.compute(env));
                 }
             }
         }
 
-        kern.run()?;
+        let kern = kern.build()?;
+        if let Some(local) = &range.local {
+            self.check_local_size(&kern, local)?;
+        }
+        let mut event = ocl::Event::empty();
+        unsafe {
+            kern.cmd().enew(&mut event).enq()?;
+        }
+        self.record(Command::Kernel, name, &event)?;
+
+        Ok(())
+    }
 
+    /// Runs a kernel with the last dimension of its range split between
+    /// `shards`, in proportion to their weights. Each device gets its own
+    /// copy of the buffer arguments. The `gather` buffers are merged back,
+    /// each device contributing the elements of its work-items, other buffers
+    /// are only read by the devices.
+    pub fn run_sharded(
+        &mut self,
+        env: &Environment,
+        name: String,
+        args: Vec<Arg>,
+        range: NDRange,
+        shards: &[(Device, f64)],
+        gather: &[String],
+    ) -> ClmanResult<()> {
+        range.check()?;
+        let last = range.global.len() - 1;
+        let step = range.local.as_ref().map_or(1, |l| l[last]);
+        let total = shards.iter().map(|(_, w)| w).sum::<f64>();
+        if shards.iter().any(|(_, w)| *w < 0.0) || total <= 0.0 {
+            return Err(ClmanError::WorkSize(format!(
+                "Cannot split {} with weights {:?}",
+                name,
+                shards.iter().map(|(_, w)| w).collect::<Vec<_>>()
+            )));
+        }
+        let mut parts = Vec::new();
+        let (mut start, mut weight) = (0, 0.0);
+        for (i, (device, w)) in shards.iter().enumerate() {
+            weight += w;
+            let end = if i == shards.len() - 1 {
+                range.global[last]
+            } else {
+                ((range.global[last] / step) as f64 * weight / total).round() as usize * step
+            };
+            parts.push((device, start, end));
+            start = end;
+        }
+
+        let mut names = Vec::new();
+        for arg in args.iter() {
+            if let Arg::Buffer(name) = arg {
+                let name = name.compute(env);
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        let items = range.global.iter().product::<usize>();
+        for n in gather.iter() {
+            if !names.contains(n) {
+                return Err(ClmanError::Buffer(format!(
+                    "Cannot gather {}, it is not a buffer argument of {}",
+                    n, name
+                )));
+            }
+            let buff = self.buffer(n)?;
+            if !buff.length.is_multiple_of(items) {
+                return Err(ClmanError::Buffer(format!(
+                    "Cannot gather {}, its {} elements cannot be split between {} work-items",
+                    n, buff.length, items
+                )));
+            }
+        }
+        let contents = names
+            .iter()
+            .map(|n| self.read_buffer::<u8>(n.clone()))
+            .collect::<ClmanResult<Vec<_>>>()?;
+
+        for &(device, start, end) in parts.iter() {
+            if start == end {
+                continue;
+            }
+            if !self.shards.contains_key(device) {
+                let gpu = GPU::new(
+                    self.source.clone(),
+                    self.build_options.clone(),
+                    Some(device),
+                    self.timings.is_some(),
+                )?;
+                self.shards.insert(device.clone(), gpu);
+            }
+            let shard = self.shards.get_mut(device).unwrap();
+            for (n, data) in names.iter().zip(contents.iter()) {
+                let buff = &self.buffers[n];
+                if !shard
+                    .buffers
+                    .get(n)
+                    .is_some_and(|b| b.buffer_type == buff.buffer_type && b.length == buff.length)
+                {
+                    shard.create_buffer(n.clone(), buff.buffer_type, buff.length)?;
+                }
+                shard.write_buffer(n.clone(), data)?;
+            }
+            let mut global = range.global.clone();
+            global[last] = end - start;
+            let mut offset = range.offset.clone().unwrap_or_else(|| vec![0; last + 1]);
+            offset[last] += start;
+            shard.run_kernel(
+                env,
+                name.clone(),
+                args.clone(),
+                NDRange {
+                    global,
+                    local: range.local.clone(),
+                    offset: Some(offset),
+                },
+            )?;
+        }
+
+        let inner = items / range.global[last];
+        let base = range.offset.as_ref().map_or(0, |o| o[last]);
+        for &(device, start, end) in parts.iter() {
+            if start == end {
+                continue;
+            }
+            let shard = self.shards.get_mut(device).unwrap();
+            for n in gather.iter() {
+                let buff = &self.buffers[n];
+                let size = buff.length / items * buff.buffer_type.size_of();
+                let (from, to) = ((base + start) * inner * size, (base + end) * inner * size);
+                if to > buff.buffer.len() {
+                    return Err(ClmanError::Buffer(format!(
+                        "Cannot gather {}, work-items {}..{} are past its end",
+                        n,
+                        base + start,
+                        base + end
+                    )));
+                }
+                let mut data = vec![0u8; to - from];
+                shard.buffers[n].buffer.read(&mut data).offset(from).enq()?;
+                buff.buffer.write(&data).offset(from).enq()?;
+            }
+            if let Some(timings) = &mut self.timings {
+                timings.extend(shard.take_timings().into_iter().map(|mut t| {
+                    t.name = format!("{} ({})", t.name, device);
+                    t
+                }));
+            }
+        }
         Ok(())
     }
 }
@@ -2,6 +2,7 @@ use crate::error::ClmanResult;
 use linked_hash_map::LinkedHashMap;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::convert::TryInto;
 use std::fs;
 use std::path::Path;
 
@@ -96,7 +97,10 @@ pub enum Value<T: Default + Clone> {
 impl Computable<String> for ValueString {
     fn compute(&self, env: &Environment) -> String {
         let mut ret = self.0.clone();
-        for (k, v) in env.as_map().iter() {
+        // Longer names first, so that `$WIDTH` does not clobber `$WIDTH_2`
+        let mut vars = env.as_map().into_iter().collect::<Vec<_>>();
+        vars.sort_by_key(|(k, _)| std::cmp::Reverse(k.len()));
+        for (k, v) in vars.iter() {
             ret = ret.replace(&format!("${}", k), v);
         }
         if ret.starts_with("$((") && ret.ends_with("))") {
@@ -110,6 +114,26 @@ impl Computable<String> for ValueString {
     }
 }
 
+impl ValueString {
+    /// Whether this is a true condition, i.e. anything but an empty string,
+    /// zero, an undefined variable or one of the YAML 1.1 false values
+    /// (`false`, `no` and `off`, in any case)
+    pub fn is_true(&self, env: &Environment) -> bool {
+        let v = self.compute(env);
+        let v = v.trim();
+        match v.parse::<f64>() {
+            Ok(n) => n != 0.0,
+            Err(_) => {
+                !(v.is_empty()
+                    || v.starts_with('$')
+                    || ["false", "no", "off"]
+                        .iter()
+                        .any(|f| v.eq_ignore_ascii_case(f)))
+            }
+        }
+    }
+}
+
 impl<T: Default + Clone + std::str::FromStr> Computable<T> for Value<T>
 where
     <T as std::str::FromStr>::Err: std::fmt::Debug,
@@ -125,10 +149,31 @@ where
     }
 }
 
+/// Work size of a single dimension, or a list of up to 3 dimensions
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum WorkSize {
+    Single(Value<usize>),
+    Multi(Vec<Value<usize>>),
+}
+
+impl Computable<Vec<usize>> for WorkSize {
+    fn compute(&self, env: &Environment) -> Vec<usize> {
+        match self {
+            WorkSize::Single(v) => vec![v.compute(env)],
+            WorkSize::Multi(v) => v.iter().map(|v| v.compute(env)).collect(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Arg {
     Buffer(ValueString),
+    Local {
+        r#type: BufferType,
+        count: Value<usize>,
+    },
     Char(Value<i8>),
     Uchar(Value<u8>),
     Short(Value<i16>),
@@ -139,6 +184,56 @@ pub enum Arg {
     Ulong(Value<u64>),
     Float(Value<f32>),
     Double(Value<f64>),
+    Char2(Vec<Value<i8>>),
+    Char3(Vec<Value<i8>>),
+    Char4(Vec<Value<i8>>),
+    Char8(Vec<Value<i8>>),
+    Char16(Vec<Value<i8>>),
+    Uchar2(Vec<Value<u8>>),
+    Uchar3(Vec<Value<u8>>),
+    Uchar4(Vec<Value<u8>>),
+    Uchar8(Vec<Value<u8>>),
+    Uchar16(Vec<Value<u8>>),
+    Short2(Vec<Value<i16>>),
+    Short3(Vec<Value<i16>>),
+    Short4(Vec<Value<i16>>),
+    Short8(Vec<Value<i16>>),
+    Short16(Vec<Value<i16>>),
+    Ushort2(Vec<Value<u16>>),
+    Ushort3(Vec<Value<u16>>),
+    Ushort4(Vec<Value<u16>>),
+    Ushort8(Vec<Value<u16>>),
+    Ushort16(Vec<Value<u16>>),
+    Int2(Vec<Value<i32>>),
+    Int3(Vec<Value<i32>>),
+    Int4(Vec<Value<i32>>),
+    Int8(Vec<Value<i32>>),
+    Int16(Vec<Value<i32>>),
+    Uint2(Vec<Value<u32>>),
+    Uint3(Vec<Value<u32>>),
+    Uint4(Vec<Value<u32>>),
+    Uint8(Vec<Value<u32>>),
+    Uint16(Vec<Value<u32>>),
+    Long2(Vec<Value<i64>>),
+    Long3(Vec<Value<i64>>),
+    Long4(Vec<Value<i64>>),
+    Long8(Vec<Value<i64>>),
+    Long16(Vec<Value<i64>>),
+    Ulong2(Vec<Value<u64>>),
+    Ulong3(Vec<Value<u64>>),
+    Ulong4(Vec<Value<u64>>),
+    Ulong8(Vec<Value<u64>>),
+    Ulong16(Vec<Value<u64>>),
+    Float2(Vec<Value<f32>>),
+    Float3(Vec<Value<f32>>),
+    Float4(Vec<Value<f32>>),
+    Float8(Vec<Value<f32>>),
+    Float16(Vec<Value<f32>>),
+    Double2(Vec<Value<f64>>),
+    Double3(Vec<Value<f64>>),
+    Double4(Vec<Value<f64>>),
+    Double8(Vec<Value<f64>>),
+    Double16(Vec<Value<f64>>),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -149,24 +244,195 @@ pub enum Storage {
         path: ValueString,
     },
     Image {
+        path: ValueString,
+        #[serde(default)]
+        x: Option<Value<usize>>,
+        #[serde(default)]
+        y: Option<Value<usize>>,
+        #[serde(default)]
+        colormap: Option<Colormap>,
+        #[serde(default)]
+        min: Option<Value<f64>>,
+        #[serde(default)]
+        max: Option<Value<f64>>,
+    },
+    Npy {
+        path: ValueString,
+        #[serde(default)]
+        shape: Option<Vec<Value<usize>>>,
+    },
+    Csv {
+        #[serde(default)]
+        path: Option<ValueString>,
+        #[serde(default)]
+        header: Option<Vec<String>>,
+        #[serde(default)]
+        limit: Option<Value<usize>>,
+    },
+    Hdr {
         path: ValueString,
         x: Value<usize>,
         y: Value<usize>,
+        #[serde(default)]
+        preview: Option<Preview>,
     },
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tonemap {
+    Reinhard,
+    Aces,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Preview {
+    pub path: ValueString,
+    #[serde(default)]
+    pub tonemap: Option<Tonemap>,
+    #[serde(default)]
+    pub exposure: Option<Value<f64>>,
+    #[serde(default)]
+    pub gamma: Option<Value<f64>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Colormap {
+    Viridis,
+    Magma,
+    Jet,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    /// The job is skipped when this is false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<ValueString>,
+    /// Jobs that have to run before this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub needs: Vec<String>,
+    #[serde(flatten)]
+    pub kind: JobKind,
+}
+
+impl Job {
+    pub fn enabled(&self, env: &Environment) -> bool {
+        match &self.when {
+            Some(when) => when.is_true(env),
+            None => true,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
-pub enum Job {
+pub enum JobKind {
     Run {
         run: ValueString,
         args: Vec<Arg>,
-        global_work_size: Value<usize>,
+        global_work_size: WorkSize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        local_work_size: Option<WorkSize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        global_work_offset: Option<WorkSize>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        devices: Vec<Shard>,
+        /// Buffers the devices write to, with a whole number of elements per
+        /// work-item, merged back after a sharded run. Required with `devices`.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        gather: Vec<ValueString>,
     },
     Save {
         save: ValueString,
         to: Storage,
     },
+    Load {
+        load: ValueString,
+        from: Storage,
+    },
+    Repeat {
+        repeat: Value<usize>,
+        jobs: LinkedHashMap<String, Job>,
+    },
+    Swap {
+        swap: (ValueString, ValueString),
+    },
+    Copy {
+        copy: BufferCopy,
+    },
+    Fill {
+        fill: BufferFill,
+    },
+    Print {
+        print: BufferPrint,
+    },
+}
+
+/// Device taking a share of a run job, proportional to its weight (1 by
+/// default)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Shard {
+    pub device: Device,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<Value<f64>>,
+}
+
+/// Device-side copy between buffers of the same type, offsets and count are
+/// in elements
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BufferCopy {
+    pub from: ValueString,
+    pub to: ValueString,
+    #[serde(default)]
+    pub src_offset: Option<Value<usize>>,
+    #[serde(default)]
+    pub dst_offset: Option<Value<usize>>,
+    #[serde(default)]
+    pub count: Option<Value<usize>>,
+}
+
+/// A single value for every component, or one value per component
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Pattern {
+    Scalar(Value<f64>),
+    Vector(Vec<Value<f64>>),
+}
+
+impl Computable<Vec<f64>> for Pattern {
+    fn compute(&self, env: &Environment) -> Vec<f64> {
+        match self {
+            Pattern::Scalar(v) => vec![v.compute(env)],
+            Pattern::Vector(v) => v.iter().map(|v| v.compute(env)).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrintFormat {
+    Values,
+    Summary,
+}
+
+/// Prints the elements `range` (`[start, end)`, whole buffer by default) of a
+/// buffer, `width` elements per row
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BufferPrint {
+    pub buffer: ValueString,
+    #[serde(default)]
+    pub range: Option<(Value<usize>, Value<usize>)>,
+    #[serde(default)]
+    pub width: Option<Value<usize>>,
+    #[serde(default)]
+    pub format: Option<PrintFormat>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BufferFill {
+    pub buffer: ValueString,
+    pub value: Pattern,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -182,36 +448,392 @@ pub enum BufferType {
     Ulong,
     Float,
     Double,
+    Char2,
+    Char3,
+    Char4,
+    Char8,
+    Char16,
+    Uchar2,
+    Uchar3,
+    Uchar4,
+    Uchar8,
+    Uchar16,
+    Short2,
+    Short3,
+    Short4,
+    Short8,
+    Short16,
+    Ushort2,
+    Ushort3,
+    Ushort4,
+    Ushort8,
+    Ushort16,
+    Int2,
+    Int3,
+    Int4,
+    Int8,
+    Int16,
+    Uint2,
+    Uint3,
+    Uint4,
+    Uint8,
+    Uint16,
+    Long2,
+    Long3,
+    Long4,
+    Long8,
+    Long16,
+    Ulong2,
+    Ulong3,
+    Ulong4,
+    Ulong8,
+    Ulong16,
+    Float2,
+    Float3,
     Float4,
+    Float8,
+    Float16,
+    Double2,
+    Double3,
+    Double4,
+    Double8,
+    Double16,
 }
 
 impl BufferType {
     pub fn size_of(&self) -> usize {
         match self {
-            Self::Char => 1,
-            Self::Uchar => 1,
-            Self::Short => 2,
-            Self::Ushort => 2,
-            Self::Int => 4,
-            Self::Uint => 4,
-            Self::Long => 8,
-            Self::Ulong => 8,
-            Self::Float => 4,
-            Self::Double => 8,
-            Self::Float4 => 16,
+            Self::Char | Self::Uchar => 1,
+            Self::Short | Self::Ushort => 2,
+            Self::Int | Self::Uint | Self::Float => 4,
+            Self::Long | Self::Ulong | Self::Double => 8,
+            // 3-component vectors are aligned like 4-component ones
+            v => v.scalar().size_of() * v.components().next_power_of_two(),
         }
     }
+
+    /// Scalar type of the components of this type
+    pub fn scalar(&self) -> BufferType {
+        match self {
+            Self::Char2 | Self::Char3 | Self::Char4 | Self::Char8 | Self::Char16 => Self::Char,
+            Self::Uchar2 | Self::Uchar3 | Self::Uchar4 | Self::Uchar8 | Self::Uchar16 => {
+                Self::Uchar
+            }
+            Self::Short2 | Self::Short3 | Self::Short4 | Self::Short8 | Self::Short16 => {
+                Self::Short
+            }
+            Self::Ushort2 | Self::Ushort3 | Self::Ushort4 | Self::Ushort8 | Self::Ushort16 => {
+                Self::Ushort
+            }
+            Self::Int2 | Self::Int3 | Self::Int4 | Self::Int8 | Self::Int16 => Self::Int,
+            Self::Uint2 | Self::Uint3 | Self::Uint4 | Self::Uint8 | Self::Uint16 => Self::Uint,
+            Self::Long2 | Self::Long3 | Self::Long4 | Self::Long8 | Self::Long16 => Self::Long,
+            Self::Ulong2 | Self::Ulong3 | Self::Ulong4 | Self::Ulong8 | Self::Ulong16 => {
+                Self::Ulong
+            }
+            Self::Float2 | Self::Float3 | Self::Float4 | Self::Float8 | Self::Float16 => {
+                Self::Float
+            }
+            Self::Double2 | Self::Double3 | Self::Double4 | Self::Double8 | Self::Double16 => {
+                Self::Double
+            }
+            scalar => *scalar,
+        }
+    }
+
+    pub fn components(&self) -> usize {
+        match self {
+            Self::Char2
+            | Self::Uchar2
+            | Self::Short2
+            | Self::Ushort2
+            | Self::Int2
+            | Self::Uint2
+            | Self::Long2
+            | Self::Ulong2
+            | Self::Float2
+            | Self::Double2 => 2,
+            Self::Char3
+            | Self::Uchar3
+            | Self::Short3
+            | Self::Ushort3
+            | Self::Int3
+            | Self::Uint3
+            | Self::Long3
+            | Self::Ulong3
+            | Self::Float3
+            | Self::Double3 => 3,
+            Self::Char4
+            | Self::Uchar4
+            | Self::Short4
+            | Self::Ushort4
+            | Self::Int4
+            | Self::Uint4
+            | Self::Long4
+            | Self::Ulong4
+            | Self::Float4
+            | Self::Double4 => 4,
+            Self::Char8
+            | Self::Uchar8
+            | Self::Short8
+            | Self::Ushort8
+            | Self::Int8
+            | Self::Uint8
+            | Self::Long8
+            | Self::Ulong8
+            | Self::Float8
+            | Self::Double8 => 8,
+            Self::Char16
+            | Self::Uchar16
+            | Self::Short16
+            | Self::Ushort16
+            | Self::Int16
+            | Self::Uint16
+            | Self::Long16
+            | Self::Ulong16
+            | Self::Float16
+            | Self::Double16 => 16,
+            _ => 1,
+        }
+    }
+
+    /// Native-endian bytes of a single component of this type
+    pub fn encode(&self, v: f64) -> Vec<u8> {
+        match self.scalar() {
+            Self::Char => (v as i8).to_ne_bytes().to_vec(),
+            Self::Uchar => (v as u8).to_ne_bytes().to_vec(),
+            Self::Short => (v as i16).to_ne_bytes().to_vec(),
+            Self::Ushort => (v as u16).to_ne_bytes().to_vec(),
+            Self::Int => (v as i32).to_ne_bytes().to_vec(),
+            Self::Uint => (v as u32).to_ne_bytes().to_vec(),
+            Self::Long => (v as i64).to_ne_bytes().to_vec(),
+            Self::Ulong => (v as u64).to_ne_bytes().to_vec(),
+            Self::Float => (v as f32).to_ne_bytes().to_vec(),
+            Self::Double => v.to_ne_bytes().to_vec(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// NumPy `descr` of the components of this type
+    pub fn dtype(&self) -> String {
+        let endian = if cfg!(target_endian = "little") {
+            "<"
+        } else {
+            ">"
+        };
+        match self.scalar() {
+            Self::Char => "|i1".into(),
+            Self::Uchar => "|u1".into(),
+            Self::Short => format!("{}i2", endian),
+            Self::Ushort => format!("{}u2", endian),
+            Self::Int => format!("{}i4", endian),
+            Self::Uint => format!("{}u4", endian),
+            Self::Long => format!("{}i8", endian),
+            Self::Ulong => format!("{}u8", endian),
+            Self::Float => format!("{}f4", endian),
+            Self::Double => format!("{}f8", endian),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Value of a single component of this type, from native-endian bytes
+    pub fn decode(&self, bytes: &[u8]) -> f64 {
+        let bytes = &bytes[..self.scalar().size_of()];
+        match self.scalar() {
+            Self::Char => i8::from_ne_bytes(bytes.try_into().unwrap()) as f64,
+            Self::Uchar => u8::from_ne_bytes(bytes.try_into().unwrap()) as f64,
+            Self::Short => i16::from_ne_bytes(bytes.try_into().unwrap()) as f64,
+            Self::Ushort => u16::from_ne_bytes(bytes.try_into().unwrap()) as f64,
+            Self::Int => i32::from_ne_bytes(bytes.try_into().unwrap()) as f64,
+            Self::Uint => u32::from_ne_bytes(bytes.try_into().unwrap()) as f64,
+            Self::Long => i64::from_ne_bytes(bytes.try_into().unwrap()) as f64,
+            Self::Ulong => u64::from_ne_bytes(bytes.try_into().unwrap()) as f64,
+            Self::Float => f32::from_ne_bytes(bytes.try_into().unwrap()) as f64,
+            Self::Double => f64::from_ne_bytes(bytes.try_into().unwrap()),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Text form of a single component of this type, from native-endian bytes
+    pub fn format(&self, bytes: &[u8]) -> String {
+        let bytes = &bytes[..self.scalar().size_of()];
+        match self.scalar() {
+            Self::Char => i8::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+            Self::Uchar => u8::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+            Self::Short => i16::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+            Self::Ushort => u16::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+            Self::Int => i32::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+            Self::Uint => u32::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+            Self::Long => i64::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+            Self::Ulong => u64::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+            Self::Float => f32::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+            Self::Double => f64::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Text form of each component of an element of this type
+    pub fn format_element(&self, elem: &[u8]) -> Vec<String> {
+        let scalar = self.scalar();
+        elem.chunks(scalar.size_of())
+            .take(self.components())
+            .map(|c| scalar.format(c))
+            .collect()
+    }
+
+    /// Unpacks elements of this type into per-component values, the inverse
+    /// of `pack`
+    pub fn unpack(&self, bytes: &[u8]) -> Vec<f64> {
+        let scalar = self.scalar();
+        bytes
+            .chunks(self.size_of())
+            .flat_map(|elem| {
+                elem.chunks(scalar.size_of())
+                    .take(self.components())
+                    .map(|c| scalar.decode(c))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Packs per-component values into elements of this type, zeroing the
+    /// padding of 3-component vectors
+    pub fn pack(&self, values: &[f64]) -> Vec<u8> {
+        let scalar = self.scalar();
+        values
+            .chunks(self.components())
+            .flat_map(|elem| {
+                let mut bytes = elem
+                    .iter()
+                    .flat_map(|&v| scalar.encode(v))
+                    .collect::<Vec<_>>();
+                if elem.len() == self.components() {
+                    bytes.resize(self.size_of(), 0);
+                }
+                bytes
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Init {
+    Constant(Value<f64>),
+    Range {
+        start: Value<f64>,
+        step: Value<f64>,
+    },
+    Uniform {
+        seed: Value<u64>,
+        min: Value<f64>,
+        max: Value<f64>,
+    },
+    Normal {
+        seed: Value<u64>,
+        mean: Value<f64>,
+        stddev: Value<f64>,
+    },
+    List(Vec<f64>),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Buffer {
     pub r#type: BufferType,
     pub count: Value<usize>,
+    #[serde(default)]
+    pub init: Option<Init>,
+}
+
+/// Allowed difference between expected and actual values. Without any
+/// tolerance the values have to match exactly, otherwise a component passes
+/// when it is within any of the given tolerances.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tolerance {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub abs: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rel: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ulp: Option<u64>,
+}
+
+/// Expected contents of a buffer after a test has run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Expect {
+    pub from: Storage,
+    #[serde(flatten)]
+    pub tolerance: Tolerance,
+}
+
+/// Arguments of a single call of a tested function, and what it should return
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Case {
+    pub args: Vec<Pattern>,
+    pub returns: Pattern,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Test {
+    #[serde(default)]
+    pub args: String,
+    #[serde(default)]
+    pub define: LinkedHashMap<String, ValueString>,
+    #[serde(flatten)]
+    pub kind: TestKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TestKind {
+    Pipeline {
+        #[serde(default)]
+        jobs: Vec<String>,
+        expect: LinkedHashMap<String, Expect>,
+    },
+    Function {
+        function: String,
+        cases: Vec<Case>,
+        #[serde(flatten)]
+        tolerance: Tolerance,
+    },
+}
+
+/// Device to run on, by its index in `clman devices`, its type (`cpu`, `gpu`
+/// or `accelerator`) or a part of its name
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Device {
+    Index(usize),
+    Name(String),
+}
+
+impl std::fmt::Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Device::Index(index) => write!(f, "{}", index),
+            Device::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl From<&str> for Device {
+    fn from(s: &str) -> Self {
+        match s.parse() {
+            Ok(index) => Device::Index(index),
+            Err(_) => Device::Name(s.to_string()),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+    /// Options passed to the OpenCL compiler
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub build_options: Vec<ValueString>,
     pub src: LinkedHashMap<String, Source>,
     #[serde(default)]
     pub buffers: LinkedHashMap<String, Buffer>,
@@ -219,6 +841,8 @@ pub struct Config {
     pub define: LinkedHashMap<String, ValueString>,
     #[serde(default)]
     pub jobs: LinkedHashMap<String, Job>,
+    #[serde(default, skip_serializing_if = "LinkedHashMap::is_empty")]
+    pub tests: LinkedHashMap<String, Test>,
 }
 
 pub fn write_config(root: &Path, conf: Config) -> ClmanResult<()> {
@@ -234,6 +858,8 @@ pub fn read_config(root: &Path) -> ClmanResult<Config> {
 pub fn default() -> Config {
     Config {
         version: VERSION.to_string(),
+        device: None,
+        build_options: vec![],
         define: Default::default(),
         src: {
             let mut src = LinkedHashMap::<String, Source>::new();
@@ -246,17 +872,48 @@ pub fn default() -> Config {
             src
         },
         buffers: Default::default(),
+        tests: Default::default(),
         jobs: {
             let mut jobs = LinkedHashMap::<String, Job>::new();
             jobs.insert(
                 "main".to_string(),
-                Job::Run {
-                    run: ValueString::from("hello_world"),
-                    args: vec![],
-                    global_work_size: Value::Static(1),
+                Job {
+                    when: None,
+                    needs: vec![],
+                    kind: JobKind::Run {
+                        run: ValueString::from("hello_world"),
+                        args: vec![],
+                        global_work_size: WorkSize::Single(Value::Static(1)),
+                        local_work_size: None,
+                        global_work_offset: None,
+                        devices: vec![],
+                        gather: vec![],
+                    },
                 },
             );
             jobs
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conditions() {
+        let mut env = Environment::new(None);
+        env.set("ON".into(), "1".into());
+        let is_true = |v: &str| ValueString::from(v).is_true(&env);
+        for v in ["true", "yes", "1", "-2.5", "$ON", "anything"].iter() {
+            assert!(is_true(v), "{:?} should be true", v);
+        }
+        for v in [
+            "", " ", "0", "0.0", "false", "False", "FALSE", "no", "No", "OFF", "$UNSET",
+        ]
+        .iter()
+        {
+            assert!(!is_true(v), "{:?} should be false", v);
+        }
+    }
+}
@@ -8,13 +8,41 @@ pub enum ClmanError {
     Git(#[from] git2::Error),
     #[error("Command Error: {stderr:?}")]
     Command { stderr: String },
-    #[error("GPU Error: {0}")]
-    Gpu(rust_gpu_tools::opencl::GPUError),
+    #[error("Image Error: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("Storage Error: {0}")]
+    Storage(String),
+    #[error("Job Error: {0}")]
+    Job(String),
+    #[error("Buffer Error: {0}")]
+    Buffer(String),
+    #[error("Size Error: buffer {name:?} is {expected} bytes, got {actual} bytes")]
+    Size {
+        name: String,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("Test Error: {0}")]
+    Test(String),
+    #[error("Work Size Error: {0}")]
+    WorkSize(String),
+    #[error("Build Error: {0}")]
+    Build(String),
+    #[error("Device Error: {0}")]
+    Device(String),
+    #[error("OpenCL Error: {0}")]
+    Ocl(ocl::Error),
 }
 pub type ClmanResult<T> = std::result::Result<T, ClmanError>;
 
-impl From<rust_gpu_tools::opencl::GPUError> for ClmanError {
-    fn from(err: rust_gpu_tools::opencl::GPUError) -> Self {
-        Self::Gpu(err)
+impl From<ocl::Error> for ClmanError {
+    fn from(err: ocl::Error) -> Self {
+        Self::Ocl(err)
+    }
+}
+
+impl From<ocl::core::Error> for ClmanError {
+    fn from(err: ocl::core::Error) -> Self {
+        Self::Ocl(err.into())
     }
 }
@@ -0,0 +1,51 @@
+use crate::conf::{BufferType, Computable, Environment, Init};
+
+/// SplitMix64, so that seeded buffers look the same on every machine
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal, using the Box-Muller transform
+    fn next_normal(&mut self) -> f64 {
+        let u = 1.0 - self.next_f64();
+        let v = self.next_f64();
+        (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+    }
+}
+
+/// Generates the initial contents of a buffer with `count` elements.
+/// Values are produced per component and converted to `buffer_type`.
+pub fn generate(env: &Environment, init: &Init, buffer_type: BufferType, count: usize) -> Vec<u8> {
+    let n = count * buffer_type.components();
+    let values: Vec<f64> = match init {
+        Init::Constant(v) => vec![v.compute(env); n],
+        Init::Range { start, step } => {
+            let (start, step) = (start.compute(env), step.compute(env));
+            (0..n).map(|i| start + step * i as f64).collect()
+        }
+        Init::Uniform { seed, min, max } => {
+            let mut rng = Rng(seed.compute(env));
+            let (min, max) = (min.compute(env), max.compute(env));
+            (0..n).map(|_| min + (max - min) * rng.next_f64()).collect()
+        }
+        Init::Normal { seed, mean, stddev } => {
+            let mut rng = Rng(seed.compute(env));
+            let (mean, stddev) = (mean.compute(env), stddev.compute(env));
+            (0..n).map(|_| mean + stddev * rng.next_normal()).collect()
+        }
+        Init::List(values) => values.clone(),
+    };
+    buffer_type.pack(&values)
+}
@@ -0,0 +1,441 @@
+use crate::conf::{self, Computable, Environment};
+use crate::{cl, error, storage};
+use linked_hash_map::LinkedHashMap;
+use std::collections::HashSet;
+use std::fs;
+
+/// Jobs picked on the command line, either by name or as a contiguous range
+/// of the top-level jobs. Nothing picked means every job.
+#[derive(Default)]
+pub struct Selection {
+    pub jobs: Vec<String>,
+    pub from: Option<String>,
+    pub until: Option<String>,
+}
+
+impl Selection {
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty() && self.from.is_none() && self.until.is_none()
+    }
+
+    /// Names of the selected jobs, in the order they should run. Jobs picked
+    /// by name bring their prerequisites along, ranges are taken as they are.
+    pub fn select(
+        &self,
+        jobs: &LinkedHashMap<String, conf::Job>,
+    ) -> error::ClmanResult<Vec<String>> {
+        let order = order(jobs)?;
+        if self.is_empty() {
+            return Ok(order);
+        }
+        let names = jobs.keys().cloned().collect::<Vec<_>>();
+        let index = |name: &String| {
+            names
+                .iter()
+                .position(|n| n == name)
+                .ok_or_else(|| error::ClmanError::Job(format!("No job named {}", name)))
+        };
+        let mut selected = HashSet::new();
+        let mut pending = self.jobs.clone();
+        while let Some(name) = pending.pop() {
+            index(&name)?;
+            if selected.insert(name.clone()) {
+                pending.extend(jobs[&name].needs.iter().cloned());
+            }
+        }
+        if self.from.is_some() || self.until.is_some() {
+            let from = self.from.as_ref().map_or(Ok(0), index)?;
+            let until = self
+                .until
+                .as_ref()
+                .map_or_else(|| Ok(names.len().saturating_sub(1)), index)?;
+            if from > until {
+                return Err(error::ClmanError::Job(format!(
+                    "Job {} (--from) comes after job {} (--until)",
+                    names[from], names[until]
+                )));
+            }
+            selected.extend(names.iter().take(until + 1).skip(from).cloned());
+        }
+        Ok(order.into_iter().filter(|n| selected.contains(n)).collect())
+    }
+}
+
+/// Names of `jobs` sorted so that every job comes after the jobs it `needs`,
+/// otherwise keeping the order they are defined in
+pub fn order(jobs: &LinkedHashMap<String, conf::Job>) -> error::ClmanResult<Vec<String>> {
+    fn visit(
+        jobs: &LinkedHashMap<String, conf::Job>,
+        name: &str,
+        path: &mut Vec<String>,
+        order: &mut Vec<String>,
+    ) -> error::ClmanResult<()> {
+        if order.iter().any(|n| n == name) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|n| n == name) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(name.to_string());
+            return Err(error::ClmanError::Job(format!(
+                "Dependency cycle: {}",
+                cycle.join(" -> ")
+            )));
+        }
+        path.push(name.to_string());
+        for need in jobs[name].needs.iter() {
+            if !jobs.contains_key(need) {
+                return Err(error::ClmanError::Job(format!(
+                    "Job {} needs {}, which does not exist",
+                    name, need
+                )));
+            }
+            visit(jobs, need, path, order)?;
+        }
+        path.pop();
+        order.push(name.to_string());
+        Ok(())
+    }
+
+    let mut order = Vec::new();
+    for name in jobs.keys() {
+        visit(jobs, name, &mut Vec::new(), &mut order)?;
+    }
+    Ok(order)
+}
+
+/// One-line summary of a job, with its names resolved in `env`
+pub fn describe(env: &Environment, job: &conf::Job) -> String {
+    let desc = match &job.kind {
+        conf::JobKind::Run { run, .. } => format!("run {}", run.compute(env)),
+        conf::JobKind::Save { save, .. } => format!("save {}", save.compute(env)),
+        conf::JobKind::Load { load, .. } => format!("load {}", load.compute(env)),
+        conf::JobKind::Repeat { repeat, .. } => format!("repeat {}", repeat.compute(env)),
+        conf::JobKind::Swap { swap: (a, b) } => {
+            format!("swap {} {}", a.compute(env), b.compute(env))
+        }
+        conf::JobKind::Copy { copy } => {
+            format!("copy {} {}", copy.from.compute(env), copy.to.compute(env))
+        }
+        conf::JobKind::Fill { fill } => format!("fill {}", fill.buffer.compute(env)),
+        conf::JobKind::Print { print } => format!("print {}", print.buffer.compute(env)),
+    };
+    let desc = match &job.when {
+        Some(when) => format!("{} (when {})", desc, when.0),
+        None => desc,
+    };
+    if job.needs.is_empty() {
+        desc
+    } else {
+        format!("{} (needs {})", desc, job.needs.join(", "))
+    }
+}
+
+/// Reads `from` into the memory layout of a `buffer_type` buffer
+pub fn load(
+    env: &Environment,
+    from: &conf::Storage,
+    buffer_type: conf::BufferType,
+) -> error::ClmanResult<Vec<u8>> {
+    let check_size = |path: &conf::ValueString, expected: (usize, usize), actual| {
+        if expected != actual {
+            return Err(error::ClmanError::Storage(format!(
+                "Image {} is {}x{}",
+                path.compute(env),
+                actual.0,
+                actual.1
+            )));
+        }
+        Ok(())
+    };
+    Ok(match from {
+        conf::Storage::Raw { path } => fs::read(path.compute(env))?,
+        conf::Storage::Image { x, y, path, .. } => {
+            let (w, h, data) = storage::load_image(&path.compute(env), buffer_type)?;
+            if let (Some(x), Some(y)) = (x, y) {
+                check_size(path, (x.compute(env), y.compute(env)), (w, h))?;
+            }
+            data
+        }
+        conf::Storage::Npy { path, shape } => storage::load_npy(
+            &path.compute(env),
+            buffer_type,
+            shape
+                .as_ref()
+                .map(|s| s.iter().map(|v| v.compute(env)).collect()),
+        )?,
+        conf::Storage::Csv { path, header, .. } => {
+            let path = path
+                .as_ref()
+                .ok_or_else(|| error::ClmanError::Storage("Loading csv requires a path".into()))?;
+            storage::load_csv(&path.compute(env), buffer_type, header.is_some())?
+        }
+        conf::Storage::Hdr { path, x, y, .. } => {
+            let (w, h, data) = storage::load_hdr(&path.compute(env), buffer_type)?;
+            check_size(path, (x.compute(env), y.compute(env)), (w, h))?;
+            data
+        }
+    })
+}
+
+pub fn run_jobs(
+    gpu: &mut cl::GPU,
+    env: &Environment,
+    jobs: &LinkedHashMap<String, conf::Job>,
+) -> error::ClmanResult<()> {
+    for name in order(jobs)? {
+        run_job(gpu, env, &jobs[&name])?;
+    }
+    Ok(())
+}
+
+pub fn run_job(gpu: &mut cl::GPU, env: &Environment, job: &conf::Job) -> error::ClmanResult<()> {
+    if !job.enabled(env) {
+        return Ok(());
+    }
+    match &job.kind {
+        conf::JobKind::Run {
+            run,
+            args,
+            global_work_size,
+            local_work_size,
+            global_work_offset,
+            devices,
+            gather,
+        } => {
+            let range = cl::NDRange {
+                global: global_work_size.compute(env),
+                local: local_work_size.as_ref().map(|l| l.compute(env)),
+                offset: global_work_offset.as_ref().map(|o| o.compute(env)),
+            };
+            // Lets arguments, like the count of `local` ones, depend on the range
+            let mut env = Environment::new(Some(env.clone()));
+            let sizes = [
+                ("GLOBAL_SIZE", Some(&range.global)),
+                ("LOCAL_SIZE", range.local.as_ref()),
+            ];
+            for (var, size) in sizes.iter() {
+                if let Some(size) = size {
+                    env.set(var.to_string(), size.iter().product::<usize>().to_string());
+                    for (i, s) in size.iter().enumerate() {
+                        env.set(format!("{}_{}", var, i), s.to_string());
+                    }
+                }
+            }
+            let env = &env;
+            if devices.is_empty() {
+                gpu.run_kernel(env, run.compute(env), args.clone(), range)?;
+            } else if gather.is_empty() {
+                // The results would stay on the devices
+                return Err(error::ClmanError::Job(format!(
+                    "Running {} on several devices needs gather: with the buffers it writes",
+                    run.compute(env)
+                )));
+            } else {
+                let shards = devices
+                    .iter()
+                    .map(|s| {
+                        (
+                            s.device.clone(),
+                            s.weight.as_ref().map_or(1.0, |w| w.compute(env)),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                let gather = gather.iter().map(|g| g.compute(env)).collect::<Vec<_>>();
+                gpu.run_sharded(env, run.compute(env), args.clone(), range, &shards, &gather)?;
+            }
+        }
+        conf::JobKind::Save { save, to } => match to {
+            conf::Storage::Raw { path } => {
+                std::fs::write(&path.compute(env), gpu.read_buffer(save.compute(env))?)?;
+            }
+            conf::Storage::Image {
+                x,
+                y,
+                path,
+                colormap,
+                min,
+                max,
+            } => {
+                let (x, y) = match (x, y) {
+                    (Some(x), Some(y)) => (x.compute(env), y.compute(env)),
+                    _ => {
+                        return Err(error::ClmanError::Storage(
+                            "Image dimensions (x, y) are required for saving".into(),
+                        ))
+                    }
+                };
+                let name = save.compute(env);
+                storage::save_image(
+                    x,
+                    y,
+                    gpu.buffer_type(name.clone())?,
+                    &gpu.read_buffer(name)?,
+                    &path.compute(env),
+                    *colormap,
+                    (
+                        min.as_ref().map(|v| v.compute(env)),
+                        max.as_ref().map(|v| v.compute(env)),
+                    ),
+                )?;
+            }
+            conf::Storage::Npy { path, shape } => {
+                let name = save.compute(env);
+                storage::save_npy(
+                    &path.compute(env),
+                    gpu.buffer_type(name.clone())?,
+                    &gpu.read_buffer(name)?,
+                    shape
+                        .as_ref()
+                        .map(|s| s.iter().map(|v| v.compute(env)).collect()),
+                )?;
+            }
+            conf::Storage::Csv {
+                path,
+                header,
+                limit,
+            } => {
+                let name = save.compute(env);
+                storage::save_csv(
+                    path.as_ref().map(|p| p.compute(env)),
+                    gpu.buffer_type(name.clone())?,
+                    &gpu.read_buffer(name)?,
+                    header.as_deref(),
+                    limit.as_ref().map(|l| l.compute(env)),
+                )?;
+            }
+            conf::Storage::Hdr {
+                path,
+                x,
+                y,
+                preview,
+            } => {
+                let name = save.compute(env);
+                let (w, h) = (x.compute(env), y.compute(env));
+                let pixels = storage::hdr_pixels(
+                    w,
+                    h,
+                    gpu.buffer_type(name.clone())?,
+                    &gpu.read_buffer(name)?,
+                )?;
+                storage::save_hdr(&path.compute(env), w, h, &pixels)?;
+                if let Some(preview) = preview {
+                    storage::save_tonemapped(
+                        &preview.path.compute(env),
+                        w,
+                        h,
+                        &pixels,
+                        preview.tonemap,
+                        preview.exposure.as_ref().map_or(0.0, |e| e.compute(env)),
+                        preview.gamma.as_ref().map_or(2.2, |g| g.compute(env)),
+                    )?;
+                }
+            }
+        },
+        conf::JobKind::Load { load, from } => {
+            let name = load.compute(env);
+            let data = self::load(env, from, gpu.buffer_type(name.clone())?)?;
+            gpu.write_buffer(name, &data)?;
+        }
+        conf::JobKind::Repeat { repeat, jobs } => {
+            for i in 0..repeat.compute(env) {
+                let mut env = Environment::new(Some(env.clone()));
+                env.set("ITERATION".into(), i.to_string());
+                run_jobs(gpu, &env, jobs)?;
+            }
+        }
+        conf::JobKind::Swap { swap: (a, b) } => {
+            gpu.swap_buffers(a.compute(env), b.compute(env))?;
+        }
+        conf::JobKind::Copy { copy } => {
+            gpu.copy_buffer(
+                copy.from.compute(env),
+                copy.to.compute(env),
+                copy.src_offset.as_ref().map_or(0, |o| o.compute(env)),
+                copy.dst_offset.as_ref().map_or(0, |o| o.compute(env)),
+                copy.count.as_ref().map(|c| c.compute(env)),
+            )?;
+        }
+        conf::JobKind::Fill { fill } => {
+            let name = fill.buffer.compute(env);
+            let buffer_type = gpu.buffer_type(name.clone())?;
+            let mut value = fill.value.compute(env);
+            if value.len() == 1 {
+                value = vec![value[0]; buffer_type.components()];
+            }
+            if value.len() != buffer_type.components() {
+                return Err(error::ClmanError::Buffer(format!(
+                    "Cannot fill a {:?} buffer with {} values",
+                    buffer_type,
+                    value.len()
+                )));
+            }
+            gpu.fill_buffer(name, &buffer_type.pack(&value))?;
+        }
+        conf::JobKind::Print { print } => {
+            let name = print.buffer.compute(env);
+            let buffer_type = gpu.buffer_type(name.clone())?;
+            let data = gpu.read_buffer::<u8>(name.clone())?;
+            let count = data.len() / buffer_type.size_of();
+            let (start, end) = match &print.range {
+                Some((start, end)) => (start.compute(env), end.compute(env).min(count)),
+                None => (0, count),
+            };
+            let data = &data[start.min(end) * buffer_type.size_of()..end * buffer_type.size_of()];
+            println!("{} ({:?}) [{}..{}]:", name, buffer_type, start, end);
+            match print.format.unwrap_or(conf::PrintFormat::Values) {
+                conf::PrintFormat::Values => storage::print_values(
+                    buffer_type,
+                    data,
+                    print.width.as_ref().map(|w| w.compute(env)),
+                ),
+                conf::PrintFormat::Summary => storage::print_summary(buffer_type, data),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jobs(yaml: &str) -> LinkedHashMap<String, conf::Job> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn order_puts_prerequisites_first() {
+        let jobs = jobs(
+            "
+blur: {run: blur, args: [], global_work_size: 1, needs: [load]}
+save: {save: out, to: {type: raw, path: out.bin}, needs: [blur, load]}
+load: {load: in, from: {type: raw, path: in.bin}}
+other: {run: other, args: [], global_work_size: 1}
+",
+        );
+        assert_eq!(order(&jobs).unwrap(), ["load", "blur", "save", "other"]);
+    }
+
+    #[test]
+    fn order_reports_cycles() {
+        let jobs = jobs(
+            "
+a: {run: a, args: [], global_work_size: 1, needs: [b]}
+b: {run: b, args: [], global_work_size: 1, needs: [c]}
+c: {run: c, args: [], global_work_size: 1, needs: [a]}
+",
+        );
+        match order(&jobs) {
+            Err(error::ClmanError::Job(msg)) => {
+                assert_eq!(msg, "Dependency cycle: a -> b -> c -> a")
+            }
+            other => panic!("Expected a cycle, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn order_reports_missing_prerequisites() {
+        let jobs = jobs("a: {run: a, args: [], global_work_size: 1, needs: [b]}");
+        assert!(matches!(order(&jobs), Err(error::ClmanError::Job(_))));
+    }
+}
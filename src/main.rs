@@ -2,40 +2,33 @@ extern crate clap;
 extern crate dirs;
 extern crate git2;
 extern crate image;
-extern crate rust_gpu_tools;
+extern crate ocl;
 extern crate sha2;
 
+mod bench;
 mod cl;
 mod conf;
 mod docker;
 mod error;
 mod git;
+mod init;
+mod job;
 mod parse;
+mod profile;
+mod sourcemap;
+mod storage;
+mod testing;
 mod utils;
 
 use crate::conf::{Computable, Environment};
 use clap::{App, Arg, SubCommand};
-use image::ImageBuffer;
 use itertools::*;
+use linked_hash_map::LinkedHashMap;
 use sha2::{Digest, Sha256};
 use std::fmt::Write;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-fn save_image_float4(w: usize, h: usize, data: Vec<(f32, f32, f32, f32)>, path: &String) {
-    assert_eq!(data.len(), w * h);
-    let img = ImageBuffer::from_fn(w as u32, h as u32, |x, y| {
-        let pix = data[y as usize * w + x as usize];
-        image::Rgba([
-            (pix.0 * 255.0) as u8,
-            (pix.1 * 255.0) as u8,
-            (pix.2 * 255.0) as u8,
-            (pix.3 * 255.0) as u8,
-        ])
-    });
-    img.save(path).unwrap();
-}
-
 pub fn cache_path() -> error::ClmanResult<PathBuf> {
     let path = dirs::home_dir().unwrap().join(".clman");
     if !Path::exists(&path) {
@@ -44,17 +37,20 @@ pub fn cache_path() -> error::ClmanResult<PathBuf> {
     Ok(path)
 }
 
-pub fn checksum(root: &Path, root_args: String) -> error::ClmanResult<String> {
-    let conf = conf::read_config(root)?;
+pub fn checksum(root: &Path, conf: &conf::Config, root_args: String) -> error::ClmanResult<String> {
     let mut hasher = Sha256::new();
     hasher.input(root_args.as_bytes());
 
-    for (k, v) in conf.define {
+    for (k, v) in conf.define.iter() {
         hasher.input(k.as_bytes());
         hasher.input(v.0.as_bytes());
     }
 
-    for (name, src) in conf.src {
+    for option in conf.build_options.iter() {
+        hasher.input(option.0.as_bytes());
+    }
+
+    for (name, src) in conf.src.iter() {
         hasher.input(name.as_bytes());
         match src {
             conf::Source::Code { code } => {
@@ -109,53 +105,76 @@ pub fn new(name: &str) -> error::ClmanResult<()> {
     Ok(())
 }
 
-pub fn source(env: &Environment, root: &Path, root_args: String) -> error::ClmanResult<String> {
+/// Generates the source of the project, along with where each of its lines
+/// come from
+pub fn source(
+    env: &Environment,
+    root: &Path,
+    conf: &conf::Config,
+    root_args: String,
+) -> error::ClmanResult<(String, sourcemap::SourceMap)> {
     fetch(root, false)?;
 
-    let cache_path = cache_path()?.join(checksum(root, root_args.clone())? + ".cl");
+    let cache_path = cache_path()?.join(checksum(root, conf, root_args.clone())? + ".cl");
+    let map_path = cache_path.with_extension("map");
 
     if Path::exists(&cache_path) {
-        return Ok(fs::read_to_string(cache_path)?);
+        let map = match fs::read_to_string(&map_path) {
+            Ok(map) => serde_yaml::from_str(&map)?,
+            Err(_) => Default::default(),
+        };
+        return Ok((fs::read_to_string(cache_path)?, map));
     }
 
-    let conf = conf::read_config(root)?;
-    let sub_env = {
-        let mut env = Environment::new(Some(env.clone()));
-        for (i, arg) in root_args.split(" ").enumerate() {
-            env.set(i.to_string(), arg.into());
-        }
-        for (k, v) in conf.define.iter() {
-            env.set(k.to_string(), v.compute(&env));
-        }
-        env
-    };
+    let sub_env = environment(env, conf, &root_args);
 
     let mut ret = String::new();
-    for (name, src) in conf.src {
-        ret.push_str(
-            &match src {
-                conf::Source::Code { code } => format!("\n{}\n", code.compute(&sub_env)),
-                conf::Source::File { path } => fs::read_to_string(&root.join(Path::new(&path)))?,
-                conf::Source::Dockerfile { dockerfile, args } => {
-                    docker::gen(root, dockerfile, args.compute(&sub_env))?
-                }
-                conf::Source::Script { script, args } => utils::get_output(
+    let mut map = sourcemap::SourceMap::default();
+    for (name, src) in conf.src.iter() {
+        let start = ret.matches('\n').count() + 1;
+        match src {
+            conf::Source::Code { code } => {
+                let code = code.compute(&sub_env);
+                map.push(format!("<code {}>", name), start + 1, &code);
+                ret.push_str(&format!("\n{}\n", code));
+            }
+            conf::Source::File { path } => {
+                let text = fs::read_to_string(&root.join(Path::new(&path)))?;
+                map.push(path.clone(), start, &text);
+                ret.push_str(&text);
+            }
+            conf::Source::Dockerfile { dockerfile, args } => {
+                let text = docker::gen(root, dockerfile.clone(), args.compute(&sub_env))?;
+                map.push(format!("<dockerfile {}>", name), start, &text);
+                ret.push_str(&text);
+            }
+            conf::Source::Script { script, args } => {
+                let text = utils::get_output(
                     &(root.join(script).to_str().unwrap().to_string()
                         + " "
                         + &args.compute(&sub_env)),
-                )?,
-                conf::Source::Package { git, args } => source(
+                )?;
+                map.push(format!("<script {}>", name), start, &text);
+                ret.push_str(&text);
+            }
+            conf::Source::Package { git, args } => {
+                let root = root.join("packages").join(utils::repo_name(git));
+                let (text, package_map) = source(
                     env,
-                    &root.join("packages").join(utils::repo_name(&git)),
+                    &root,
+                    &conf::read_config(&root)?,
                     args.compute(&sub_env),
-                )?,
-            }[..],
-        );
+                )?;
+                map.extend(git, package_map, start);
+                ret.push_str(&text);
+            }
+        }
     }
 
     fs::write(cache_path, ret.clone())?;
+    fs::write(map_path, serde_yaml::to_string(&map)?)?;
 
-    Ok(ret)
+    Ok((ret, map))
 }
 
 pub fn fetch(root: &Path, force: bool) -> error::ClmanResult<()> {
@@ -168,54 +187,441 @@ pub fn fetch(root: &Path, force: bool) -> error::ClmanResult<()> {
     Ok(())
 }
 
-pub fn run(env: &Environment, root: &Path, root_args: String) -> error::ClmanResult<()> {
-    let conf = conf::read_config(root)?;
-    let src = source(env, root, root_args.clone())?;
+/// Jobs loading images into buffers, including the ones in `repeat` jobs
+fn image_loads(jobs: &LinkedHashMap<String, conf::Job>) -> Vec<&conf::Job> {
+    let mut ret = Vec::new();
+    for (_, job) in jobs.iter() {
+        match &job.kind {
+            conf::JobKind::Load {
+                from: conf::Storage::Image { .. },
+                ..
+            }
+            | conf::JobKind::Load {
+                from: conf::Storage::Hdr { .. },
+                ..
+            } => ret.push(job),
+            conf::JobKind::Repeat { jobs, .. } => ret.extend(image_loads(jobs)),
+            _ => {}
+        }
+    }
+    ret
+}
 
-    let mut env = env.clone();
+/// Sets `<buffer>_WIDTH` and `<buffer>_HEIGHT` for an image load job, unless
+/// its buffer or path depend on variables `env` does not have. Returns whether
+/// they were set.
+fn set_image_size(env: &mut Environment, job: &conf::Job) -> error::ClmanResult<bool> {
+    if let conf::JobKind::Load { load, from } = &job.kind {
+        let name = load.compute(env);
+        let (w, h) = match from {
+            conf::Storage::Image { path, .. } | conf::Storage::Hdr { path, .. } => {
+                let path = path.compute(env);
+                if name.contains('$') || path.contains('$') {
+                    return Ok(false);
+                }
+                match from {
+                    conf::Storage::Image { .. } => {
+                        let (w, h) = image::image_dimensions(path)?;
+                        (w as usize, h as usize)
+                    }
+                    _ => storage::hdr_dimensions(&path)?,
+                }
+            }
+            _ => return Ok(false),
+        };
+        env.set(format!("{}_WIDTH", name), w.to_string());
+        env.set(format!("{}_HEIGHT", name), h.to_string());
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Child of `env` holding the root args as `$0`, `$1`, ..., the sizes of
+/// the images loaded by `conf` as `$<buffer>_WIDTH` and `$<buffer>_HEIGHT`,
+/// and the defines of `conf`
+fn environment(env: &Environment, conf: &conf::Config, root_args: &str) -> Environment {
+    let mut env = Environment::new(Some(env.clone()));
     for (i, arg) in root_args.split(" ").enumerate() {
         env.set(i.to_string(), arg.into());
     }
+    // Image sizes are set as soon as their path is known, so that defines can
+    // use them. Images that cannot be read are reported by `prepare`.
+    let mut images = image_loads(&conf.jobs);
+    images.retain(|job| !set_image_size(&mut env, job).unwrap_or(true));
     for (k, v) in conf.define.iter() {
         env.set(k.to_string(), v.compute(&env));
+        images.retain(|job| !set_image_size(&mut env, job).unwrap_or(true));
     }
+    env
+}
 
-    let mut gpu = cl::GPU::new(src)?;
+/// Settings given on the command line, taking precedence over clman.yaml
+#[derive(Default)]
+pub struct Overrides {
+    pub device: Option<String>,
+    /// Appended to the build options of clman.yaml
+    pub build_options: Vec<String>,
+}
+
+impl Overrides {
+    pub fn apply(&self, mut conf: conf::Config) -> conf::Config {
+        if let Some(device) = &self.device {
+            conf.device = Some(device.as_str().into());
+        }
+        conf.build_options.extend(
+            self.build_options
+                .iter()
+                .map(|o| conf::ValueString(o.clone())),
+        );
+        conf
+    }
+}
+
+/// Options the program of `conf` is compiled with
+fn build_options(env: &Environment, conf: &conf::Config) -> String {
+    conf.build_options.iter().map(|o| o.compute(env)).join(" ")
+}
+
+/// Builds the program of `conf` and creates its buffers, returning the device
+/// and the environment the jobs run in
+pub fn prepare(
+    env: &Environment,
+    root: &Path,
+    conf: &conf::Config,
+    root_args: String,
+    profile: bool,
+) -> error::ClmanResult<(cl::GPU, Environment)> {
+    let (src, map) = source(env, root, conf, root_args.clone())?;
+    let mut env = environment(env, conf, &root_args);
+
+    // Images whose path depends on defines
+    for job in image_loads(&conf.jobs) {
+        if job.enabled(&env) {
+            set_image_size(&mut env, job)?;
+        }
+    }
+
+    let mut gpu = cl::GPU::new(
+        src,
+        build_options(&env, conf),
+        conf.device.as_ref(),
+        profile,
+    )
+    .map_err(|e| map.explain(e))?;
     for (name, buff) in conf.buffers.iter() {
-        gpu.create_buffer(name.clone(), buff.r#type, buff.count.compute(&env))?;
-    }
-    for (_, job) in conf.jobs.iter() {
-        match job {
-            conf::Job::Run {
-                run,
-                args,
-                global_work_size,
-            } => {
-                gpu.run_kernel(
-                    &env,
-                    run.compute(&env),
-                    args.clone(),
-                    global_work_size.compute(&env),
-                )?;
-            }
-            conf::Job::Save { save, to } => match to {
-                conf::Storage::Raw { path } => {
-                    std::fs::write(&path.compute(&env), gpu.read_buffer(save.compute(&env))?)?;
+        let count = buff.count.compute(&env);
+        gpu.create_buffer(name.clone(), buff.r#type, count)?;
+        if let Some(init) = &buff.init {
+            gpu.write_buffer(
+                name.clone(),
+                &init::generate(&env, init, buff.r#type, count),
+            )?;
+        }
+    }
+    Ok((gpu, env))
+}
+
+/// Runs the selected jobs of `conf`, returning the device with the final
+/// state of the buffers and the environment the jobs ran in
+pub fn execute(
+    env: &Environment,
+    root: &Path,
+    conf: &conf::Config,
+    root_args: String,
+    selection: &job::Selection,
+) -> error::ClmanResult<(cl::GPU, Environment)> {
+    let (mut gpu, env) = prepare(env, root, conf, root_args, false)?;
+    for name in selection.select(&conf.jobs)? {
+        job::run_job(&mut gpu, &env, &conf.jobs[&name])?;
+    }
+    Ok((gpu, env))
+}
+
+/// Runs the selected jobs, printing the profiled commands when `profile` is
+/// set and writing them as a Chrome trace to `trace` when given
+pub fn run(
+    env: &Environment,
+    root: &Path,
+    root_args: String,
+    selection: &job::Selection,
+    profile: bool,
+    trace: Option<&str>,
+    overrides: &Overrides,
+) -> error::ClmanResult<()> {
+    let conf = overrides.apply(conf::read_config(root)?);
+    if !profile && trace.is_none() {
+        execute(env, root, &conf, root_args, selection)?;
+        return Ok(());
+    }
+    let (mut gpu, env) = prepare(env, root, &conf, root_args, true)?;
+    let mut timeline = profile::Timeline::default();
+    timeline.add("(buffers)", gpu.take_timings());
+    for name in selection.select(&conf.jobs)? {
+        job::run_job(&mut gpu, &env, &conf.jobs[&name])?;
+        timeline.add(&name, gpu.take_timings());
+    }
+    if profile {
+        timeline.print();
+    }
+    if let Some(path) = trace {
+        timeline.write_trace(path)?;
+    }
+    Ok(())
+}
+
+/// Runs the selected jobs `warmup` times, then times them over `iterations`
+/// more runs using OpenCL profiling events
+pub fn bench(
+    env: &Environment,
+    root: &Path,
+    root_args: String,
+    selection: &job::Selection,
+    warmup: usize,
+    iterations: usize,
+    overrides: &Overrides,
+) -> error::ClmanResult<()> {
+    let conf = overrides.apply(conf::read_config(root)?);
+    let (mut gpu, env) = prepare(env, root, &conf, root_args, true)?;
+    let names = selection.select(&conf.jobs)?;
+    for _ in 0..warmup {
+        for name in names.iter() {
+            job::run_job(&mut gpu, &env, &conf.jobs[name])?;
+        }
+    }
+    gpu.take_timings();
+
+    let mut samples = bench::Samples::default();
+    for _ in 0..iterations {
+        for name in names.iter() {
+            job::run_job(&mut gpu, &env, &conf.jobs[name])?;
+            samples.add(name, gpu.take_timings());
+        }
+    }
+    samples.report();
+    Ok(())
+}
+
+/// Runs one test, returning its max error and a line for each mismatch
+fn run_test(
+    env: &Environment,
+    root: &Path,
+    conf: &conf::Config,
+    test: &conf::Test,
+) -> error::ClmanResult<(f64, Vec<String>)> {
+    let mut failures = Vec::new();
+    let mut max_error = 0f64;
+    match &test.kind {
+        conf::TestKind::Pipeline { jobs, expect } => {
+            let selection = job::Selection {
+                jobs: jobs.clone(),
+                ..Default::default()
+            };
+            let (mut gpu, env) = execute(env, root, conf, test.args.clone(), &selection)?;
+            for (buffer, expect) in expect.iter() {
+                let buffer_type = gpu.buffer_type(buffer.clone())?;
+                let expected = job::load(&env, &expect.from, buffer_type)?;
+                let actual = gpu.read_buffer::<u8>(buffer.clone())?;
+                let comparison =
+                    testing::compare(buffer, buffer_type, &expect.tolerance, &expected, &actual)?;
+                max_error = max_error.max(comparison.max_error);
+                if let Some((index, component)) = comparison.mismatch {
+                    failures.push(format!(
+                        "  {}: max error {}, first mismatch at [{}].{}",
+                        buffer, comparison.max_error, index, component
+                    ));
                 }
-                conf::Storage::Image { x, y, path } => {
-                    save_image_float4(
-                        x.compute(&env),
-                        y.compute(&env),
-                        gpu.read_buffer(save.compute(&env))?,
-                        &path.compute(&env),
-                    );
+            }
+        }
+        conf::TestKind::Function {
+            function,
+            cases,
+            tolerance,
+        } => {
+            let (src, map) = source(env, root, conf, test.args.clone())?;
+            let env = environment(env, conf, &test.args);
+            let (returns, expected, actual) = testing::run_function(
+                &env,
+                src,
+                build_options(&env, conf),
+                conf.device.as_ref(),
+                function,
+                cases,
+            )
+            .map_err(|e| map.explain(e))?;
+            let comparison = testing::compare(function, returns, tolerance, &expected, &actual)?;
+            max_error = comparison.max_error;
+            if let Some((index, component)) = comparison.mismatch {
+                let size = returns.size_of();
+                let element = |data: &[u8]| {
+                    returns
+                        .format_element(&data[index * size..(index + 1) * size])
+                        .join(", ")
+                };
+                failures.push(format!(
+                    "  case {}: returned ({}), expected ({}), first mismatch at component {}",
+                    index,
+                    element(&actual),
+                    element(&expected),
+                    component
+                ));
+            }
+        }
+    }
+    Ok((max_error, failures))
+}
+
+/// Runs the tests of the project, returning whether all of them passed
+pub fn test(
+    env: &Environment,
+    root: &Path,
+    names: &[String],
+    overrides: &Overrides,
+) -> error::ClmanResult<bool> {
+    let base = overrides.apply(conf::read_config(root)?);
+    let mut passed = true;
+    for (name, test) in base.tests.iter() {
+        if !names.is_empty() && !names.contains(name) {
+            continue;
+        }
+        let mut conf = base.clone();
+        for (k, v) in test.define.iter() {
+            match conf.define.get_mut(k) {
+                Some(value) => *value = v.clone(),
+                None => {
+                    conf.define.insert(k.clone(), v.clone());
                 }
-            },
+            }
+        }
+        let (max_error, failures) = match run_test(env, root, &conf, test) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                println!("test {} ... FAILED", name);
+                println!("  {}", e);
+                passed = false;
+                continue;
+            }
+        };
+        if failures.is_empty() {
+            println!("test {} ... ok (max error {})", name, max_error);
+        } else {
+            println!("test {} ... FAILED", name);
+            for failure in failures {
+                println!("{}", failure);
+            }
+            passed = false;
         }
     }
+    Ok(passed)
+}
+
+pub fn devices() -> error::ClmanResult<()> {
+    let devices = cl::devices()?;
+    for (i, (platform, device)) in devices.iter().enumerate() {
+        if i == 0 || devices[i - 1].0.as_core() != platform.as_core() {
+            println!("{} ({})", platform.name()?, platform.vendor()?);
+        }
+        println!(
+            "  [{}] {} ({})",
+            i,
+            device.name()?,
+            cl::device_type(device)?
+        );
+        if let ocl::enums::DeviceInfoResult::GlobalMemSize(size) =
+            device.info(ocl::enums::DeviceInfo::GlobalMemSize)?
+        {
+            println!("      Memory: {} MiB", size / (1 << 20));
+        }
+        if let ocl::enums::DeviceInfoResult::MaxComputeUnits(units) =
+            device.info(ocl::enums::DeviceInfo::MaxComputeUnits)?
+        {
+            println!("      Compute units: {}", units);
+        }
+        if let ocl::enums::DeviceInfoResult::Extensions(extensions) =
+            device.info(ocl::enums::DeviceInfo::Extensions)?
+        {
+            println!("      Extensions: {}", extensions.trim());
+        }
+    }
+    Ok(())
+}
+
+pub fn jobs(env: &Environment, root: &Path, root_args: String) -> error::ClmanResult<()> {
+    fn print(env: &Environment, jobs: &LinkedHashMap<String, conf::Job>, depth: usize) {
+        for (name, job) in jobs.iter() {
+            println!(
+                "{}{}: {}",
+                "  ".repeat(depth),
+                name,
+                job::describe(env, job)
+            );
+            if let conf::JobKind::Repeat { jobs, .. } = &job.kind {
+                print(env, jobs, depth + 1);
+            }
+        }
+    }
+    let conf = conf::read_config(root)?;
+    print(&environment(env, &conf, &root_args), &conf.jobs, 0);
     Ok(())
 }
 
+/// Command line arguments picking the jobs to run
+fn selection_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("job")
+            .long("job")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Run only this job (Can be repeated)"),
+        Arg::with_name("from")
+            .long("from")
+            .takes_value(true)
+            .help("Run jobs starting from this one"),
+        Arg::with_name("until")
+            .long("until")
+            .takes_value(true)
+            .help("Run jobs up to and including this one"),
+    ]
+}
+
+/// Command line arguments overriding clman.yaml
+fn override_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("device")
+            .long("device")
+            .takes_value(true)
+            .help("Device index, type (cpu, gpu, accelerator) or part of its name"),
+        Arg::with_name("build-option")
+            .long("build-option")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .allow_hyphen_values(true)
+            .help("Pass this option to the OpenCL compiler (Can be repeated)"),
+    ]
+}
+
+fn overrides(matches: &clap::ArgMatches) -> Overrides {
+    Overrides {
+        device: matches.value_of("device").map(String::from),
+        build_options: matches
+            .values_of("build-option")
+            .map(|vals| vals.map(String::from).collect())
+            .unwrap_or_default(),
+    }
+}
+
+fn selection(matches: &clap::ArgMatches) -> job::Selection {
+    job::Selection {
+        jobs: matches
+            .values_of("job")
+            .map(|vals| vals.map(String::from).collect())
+            .unwrap_or_default(),
+        from: matches.value_of("from").map(String::from),
+        until: matches.value_of("until").map(String::from),
+    }
+}
+
 fn main() {
     let matches = App::new("Clman")
         .version(conf::VERSION)
@@ -234,8 +640,57 @@ fn main() {
         .subcommand(
             SubCommand::with_name("run")
                 .arg(Arg::with_name("ARGS").min_values(1))
+                .args(&selection_args())
+                .args(&override_args())
+                .arg(
+                    Arg::with_name("profile")
+                        .long("profile")
+                        .help("Print the timings of kernel launches and transfers"),
+                )
+                .arg(
+                    Arg::with_name("trace")
+                        .long("trace")
+                        .takes_value(true)
+                        .help("Write the timings as a Chrome trace to this file"),
+                )
                 .about("Run the project in current directory"),
         )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .arg(Arg::with_name("ARGS").min_values(1))
+                .args(&selection_args())
+                .args(&override_args())
+                .arg(
+                    Arg::with_name("warmup")
+                        .long("warmup")
+                        .takes_value(true)
+                        .default_value("3")
+                        .help("Untimed runs before measuring"),
+                )
+                .arg(
+                    Arg::with_name("iterations")
+                        .long("iterations")
+                        .short("n")
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("Timed runs"),
+                )
+                .about("Time the kernels of the project in current directory"),
+        )
+        .subcommand(
+            SubCommand::with_name("jobs")
+                .arg(Arg::with_name("ARGS").min_values(1))
+                .about("List the jobs of the project in current directory"),
+        )
+        .subcommand(
+            SubCommand::with_name("test")
+                .arg(Arg::with_name("TESTS").min_values(1))
+                .args(&override_args())
+                .about("Run the tests of the project in current directory"),
+        )
+        .subcommand(
+            SubCommand::with_name("devices").about("List available OpenCL platforms and devices"),
+        )
         .subcommand(SubCommand::with_name("gen").about("Generate final OpenCL source code"))
         .subcommand(SubCommand::with_name("fetch").about("Fetch git dependencies"))
         .subcommand(SubCommand::with_name("clean").about("Clean cache"))
@@ -254,11 +709,72 @@ fn main() {
             .values_of("ARGS")
             .map(|mut vals| vals.join(" "))
             .unwrap_or_default();
-        run(&env, Path::new("."), args.into()).unwrap();
+        run(
+            &env,
+            Path::new("."),
+            args.into(),
+            &selection(matches),
+            matches.is_present("profile"),
+            matches.value_of("trace"),
+            &overrides(matches),
+        )
+        .unwrap();
+    }
+
+    if let Some(matches) = matches.subcommand_matches("bench") {
+        let args = matches
+            .values_of("ARGS")
+            .map(|mut vals| vals.join(" "))
+            .unwrap_or_default();
+        let count = |name| {
+            matches
+                .value_of(name)
+                .unwrap()
+                .parse::<usize>()
+                .unwrap_or_else(|_| panic!("--{} must be a number", name))
+        };
+        bench(
+            &env,
+            Path::new("."),
+            args,
+            &selection(matches),
+            count("warmup"),
+            count("iterations"),
+            &overrides(matches),
+        )
+        .unwrap();
+    }
+
+    if let Some(matches) = matches.subcommand_matches("test") {
+        let names = matches
+            .values_of("TESTS")
+            .map(|vals| vals.map(String::from).collect::<Vec<_>>())
+            .unwrap_or_default();
+        if !test(&env, Path::new("."), &names, &overrides(matches)).unwrap() {
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("jobs") {
+        let args = matches
+            .values_of("ARGS")
+            .map(|mut vals| vals.join(" "))
+            .unwrap_or_default();
+        jobs(&env, Path::new("."), args).unwrap();
+    }
+
+    if let Some(_matches) = matches.subcommand_matches("devices") {
+        devices().unwrap();
     }
 
     if let Some(_matches) = matches.subcommand_matches("gen") {
-        println!("{}", source(&env, Path::new("."), String::new()).unwrap());
+        let conf = conf::read_config(Path::new(".")).unwrap();
+        println!(
+            "{}",
+            source(&env, Path::new("."), &conf, String::new())
+                .unwrap()
+                .0
+        );
     }
 
     if let Some(_matches) = matches.subcommand_matches("fetch") {
@@ -270,7 +786,12 @@ fn main() {
     }
 
     if let Some(_matches) = matches.subcommand_matches("list") {
-        for f in parse::list_functions(source(&env, Path::new("."), String::new()).unwrap()) {
+        let conf = conf::read_config(Path::new(".")).unwrap();
+        for f in parse::list_functions(
+            source(&env, Path::new("."), &conf, String::new())
+                .unwrap()
+                .0,
+        ) {
             println!("{}", f);
         }
     }
@@ -0,0 +1,116 @@
+use crate::cl::{Command, Timing};
+use crate::error::ClmanResult;
+use std::fmt::Write;
+use std::fs;
+
+/// Profiled commands of a run, with the jobs that enqueued them
+#[derive(Default)]
+pub struct Timeline(Vec<(String, Timing)>);
+
+fn command_name(command: Command) -> &'static str {
+    match command {
+        Command::Kernel => "kernel",
+        Command::Read => "read",
+        Command::Write => "write",
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut ret = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(&mut ret, "\\u{:04x}", c as u32).unwrap(),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+impl Timeline {
+    pub fn add(&mut self, job: &str, timings: Vec<Timing>) {
+        self.0
+            .extend(timings.into_iter().map(|t| (job.to_string(), t)));
+    }
+
+    /// Device timestamp the other timestamps are shown relative to
+    fn origin(&self) -> u64 {
+        self.0.iter().map(|(_, t)| t.queued).min().unwrap_or(0)
+    }
+
+    /// Prints every command with its timestamps, in milliseconds since the
+    /// first command was queued
+    pub fn print(&self) {
+        let origin = self.origin();
+        let ms = |ns: u64| (ns - origin) as f64 / 1e6;
+        let width = self
+            .0
+            .iter()
+            .map(|(job, t)| job.len().max(t.name.len()))
+            .max()
+            .unwrap_or(0)
+            .max(4);
+        println!(
+            "{:<width$} {:<6} {:<width$} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "job",
+            "cmd",
+            "name",
+            "queued",
+            "submit",
+            "start",
+            "end",
+            "duration",
+            width = width
+        );
+        for (job, t) in self.0.iter() {
+            println!(
+                "{:<width$} {:<6} {:<width$} {:>10.3} {:>10.3} {:>10.3} {:>10.3} {:>10.3}",
+                job,
+                command_name(t.command),
+                t.name,
+                ms(t.queued),
+                ms(t.submit),
+                ms(t.start),
+                ms(t.end),
+                t.duration() as f64 / 1e6,
+                width = width
+            );
+        }
+    }
+
+    /// Writes the timeline in the Chrome trace event format, with kernels and
+    /// transfers on separate tracks
+    pub fn write_trace(&self, path: &str) -> ClmanResult<()> {
+        let origin = self.origin();
+        let us = |ns: u64| (ns - origin) as f64 / 1e3;
+        let mut events = vec![
+            r#"{"name":"thread_name","ph":"M","pid":0,"tid":0,"args":{"name":"kernels"}}"#
+                .to_string(),
+            r#"{"name":"thread_name","ph":"M","pid":0,"tid":1,"args":{"name":"transfers"}}"#
+                .to_string(),
+        ];
+        for (job, t) in self.0.iter() {
+            events.push(format!(
+                r#"{{"name":{},"cat":"{}","ph":"X","pid":0,"tid":{},"ts":{},"dur":{},"args":{{"job":{},"queued":{},"submit":{}}}}}"#,
+                json_string(&t.name),
+                command_name(t.command),
+                if t.command == Command::Kernel { 0 } else { 1 },
+                us(t.start),
+                t.duration() as f64 / 1e3,
+                json_string(job),
+                us(t.queued),
+                us(t.submit)
+            ));
+        }
+        fs::write(
+            path,
+            format!(
+                "{{\"traceEvents\":[\n{}\n],\"displayTimeUnit\":\"ms\"}}\n",
+                events.join(",\n")
+            ),
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use crate::error::ClmanError;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+/// Lines of the generated source coming from a single `src:` entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Segment {
+    /// Where the lines come from, e.g. `src/color.cl` or `<code define.cl>`
+    pub origin: String,
+    /// First line of the segment in the generated source, starting from 1
+    pub start: usize,
+    pub lines: usize,
+}
+
+/// Where each line of a generated source comes from
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SourceMap(pub Vec<Segment>);
+
+impl SourceMap {
+    pub fn push(&mut self, origin: String, start: usize, text: &str) {
+        self.0.push(Segment {
+            origin,
+            start,
+            lines: text.lines().count(),
+        });
+    }
+
+    /// Adds the segments of a package source starting at line `start`
+    pub fn extend(&mut self, package: &str, other: SourceMap, start: usize) {
+        self.0.extend(other.0.into_iter().map(|s| Segment {
+            origin: format!("package {}:{}", package, s.origin),
+            start: s.start + start - 1,
+            lines: s.lines,
+        }));
+    }
+
+    /// Origin and line within it of a line of the generated source
+    pub fn locate(&self, line: usize) -> Option<(&str, usize)> {
+        self.0
+            .iter()
+            .find(|s| s.start <= line && line < s.start + s.lines)
+            .map(|s| (s.origin.as_str(), line - s.start + 1))
+    }
+
+    /// Replaces the positions in a compiler log with their original ones
+    pub fn rewrite(&self, log: &str) -> String {
+        let re = Regex::new(r"(?m)^(<kernel>|<source>|[^\s:]*\.cl)?:(\d+):(\d+:)?").unwrap();
+        re.replace_all(log, |cap: &Captures| {
+            match self.locate(cap[2].parse().unwrap()) {
+                Some((origin, line)) => format!(
+                    "{}:{}:{}",
+                    origin,
+                    line,
+                    cap.get(3).map_or("", |c| c.as_str())
+                ),
+                None => cap[0].to_string(),
+            }
+        })
+        .into_owned()
+    }
+
+    /// Rewrites the build log of a failed program build
+    pub fn explain(&self, err: ClmanError) -> ClmanError {
+        if let ClmanError::Ocl(ocl_err) = &err {
+            if let ocl::error::ErrorKind::OclCore(core_err) = ocl_err.kind() {
+                if let ocl::core::ErrorKind::ProgramBuild(_) = core_err.kind() {
+                    return ClmanError::Build(self.rewrite(&core_err.to_string()));
+                }
+            }
+        }
+        err
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> SourceMap {
+        let mut map = SourceMap::default();
+        map.push("<code define.cl>".into(), 1, "#define N 4\n");
+        map.push("src/main.cl".into(), 2, "a\nb\nc\n");
+        let mut package = SourceMap::default();
+        package.push("src/complex.cl".into(), 1, "d\ne\n");
+        map.extend("user/repo", package, 5);
+        map
+    }
+
+    #[test]
+    fn rewrite_kernel_positions() {
+        assert_eq!(
+            map().rewrite("<kernel>:3:5: error: use of undeclared identifier 'x'"),
+            "src/main.cl:2:5: error: use of undeclared identifier 'x'"
+        );
+        assert_eq!(
+            map().rewrite("<kernel>:1:9: note: expanded from macro 'N'"),
+            "<code define.cl>:1:9: note: expanded from macro 'N'"
+        );
+    }
+
+    #[test]
+    fn rewrite_file_lines_without_column() {
+        assert_eq!(
+            map().rewrite("input.cl:2: warning: unused variable"),
+            "src/main.cl:1: warning: unused variable"
+        );
+    }
+
+    #[test]
+    fn rewrite_package_offsets() {
+        assert_eq!(
+            map().rewrite("<source>:6:1: error: expected ';'"),
+            "package user/repo:src/complex.cl:2:1: error: expected ';'"
+        );
+    }
+
+    #[test]
+    fn rewrite_keeps_unknown_lines_and_other_text() {
+        let log = "<kernel>:42:1: error: unknown type name\n1 error generated.";
+        assert_eq!(map().rewrite(log), log);
+    }
+}
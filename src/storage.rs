@@ -0,0 +1,670 @@
+use crate::conf::{BufferType, Colormap, Tonemap};
+use crate::error::{ClmanError, ClmanResult};
+use image::codecs::hdr::{HdrDecoder, HdrEncoder};
+use image::{GenericImageView, GrayImage, RgbImage, RgbaImage};
+use regex::Regex;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+const VIRIDIS: [[f64; 3]; 7] = [
+    [0.277727327, 0.005407345, 0.334099805],
+    [0.105093043, 1.404613530, 1.384590163],
+    [-0.330861829, 0.214847559, 0.095095163],
+    [-4.634230499, -5.799100973, -19.332440956],
+    [6.228269936, 14.179933367, 56.690552601],
+    [4.776384998, -13.745145378, -65.353032633],
+    [-5.435455856, 4.645852612, 26.312435250],
+];
+
+const MAGMA: [[f64; 3]; 7] = [
+    [-0.002136485, -0.000749655, -0.005386128],
+    [0.251660541, 0.677523244, 2.494026599],
+    [8.353717279, -3.577719515, 0.314467903],
+    [-27.668733086, 14.264730781, -13.649213188],
+    [52.176139812, -27.943606072, 12.944169442],
+    [-50.768525365, 29.046582821, 4.234152994],
+    [18.655705066, -11.489773520, -5.601961509],
+];
+
+/// Polynomial fits of the matplotlib colormaps, `t` in [0, 1]
+fn polynomial(coeffs: &[[f64; 3]; 7], t: f64) -> [f64; 3] {
+    let mut rgb = [0.0; 3];
+    for (i, c) in rgb.iter_mut().enumerate() {
+        *c = coeffs.iter().rev().fold(0.0, |acc, k| acc * t + k[i]);
+    }
+    rgb
+}
+
+fn apply_colormap(colormap: Colormap, t: f64) -> image::Rgb<u8> {
+    let t = t.clamp(0.0, 1.0);
+    let rgb = match colormap {
+        Colormap::Viridis => polynomial(&VIRIDIS, t),
+        Colormap::Magma => polynomial(&MAGMA, t),
+        Colormap::Jet => [
+            1.5 - (4.0 * t - 3.0).abs(),
+            1.5 - (4.0 * t - 2.0).abs(),
+            1.5 - (4.0 * t - 1.0).abs(),
+        ],
+    };
+    image::Rgb([
+        (rgb[0] * 255.0) as u8,
+        (rgb[1] * 255.0) as u8,
+        (rgb[2] * 255.0) as u8,
+    ])
+}
+
+/// Writes a buffer as an image, depending on its type:
+///  - `Float4` and `Uchar4` buffers are written as RGBA pixels
+///  - Scalar buffers are mapped from `range` (Defaults to 0..255 for `Uchar`
+///    and 0..1 otherwise) to grayscale, or to `colormap` if given
+pub fn save_image(
+    w: usize,
+    h: usize,
+    buffer_type: BufferType,
+    data: &[u8],
+    path: &str,
+    colormap: Option<Colormap>,
+    range: (Option<f64>, Option<f64>),
+) -> ClmanResult<()> {
+    if data.len() != w * h * buffer_type.size_of() {
+        return Err(ClmanError::Storage(format!(
+            "Cannot save {} bytes of {:?} as a {}x{} image",
+            data.len(),
+            buffer_type,
+            w,
+            h
+        )));
+    }
+    let (w, h) = (w as u32, h as u32);
+    match buffer_type {
+        BufferType::Float4 => {
+            let values = buffer_type.unpack(data);
+            let img = RgbaImage::from_fn(w, h, |x, y| {
+                let pix = &values[(y * w + x) as usize * 4..];
+                image::Rgba([
+                    (pix[0] * 255.0) as u8,
+                    (pix[1] * 255.0) as u8,
+                    (pix[2] * 255.0) as u8,
+                    (pix[3] * 255.0) as u8,
+                ])
+            });
+            img.save(path)?;
+        }
+        BufferType::Uchar4 => {
+            RgbaImage::from_raw(w, h, data.to_vec())
+                .unwrap()
+                .save(path)?;
+        }
+        t if t.components() == 1 => {
+            let default_max = if t == BufferType::Uchar { 255.0 } else { 1.0 };
+            let (min, max) = (range.0.unwrap_or(0.0), range.1.unwrap_or(default_max));
+            let values = t
+                .unpack(data)
+                .into_iter()
+                .map(|v| (v - min) / (max - min))
+                .collect::<Vec<_>>();
+            match colormap {
+                Some(colormap) => {
+                    let img = RgbImage::from_fn(w, h, |x, y| {
+                        apply_colormap(colormap, values[(y * w + x) as usize])
+                    });
+                    img.save(path)?;
+                }
+                None => {
+                    let img = GrayImage::from_fn(w, h, |x, y| {
+                        image::Luma([(values[(y * w + x) as usize] * 255.0) as u8])
+                    });
+                    img.save(path)?;
+                }
+            }
+        }
+        t => {
+            return Err(ClmanError::Storage(format!(
+                "Cannot save a {:?} buffer as an image",
+                t
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Decodes an image into the memory layout of a buffer. `Float4` buffers get
+/// normalized RGBA pixels, `Uchar4` buffers get RGBA8 pixels and `Uchar`
+/// buffers get 8-bit grayscale pixels.
+pub fn load_image(path: &str, buffer_type: BufferType) -> ClmanResult<(usize, usize, Vec<u8>)> {
+    let img = image::open(path)?;
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let data = match buffer_type {
+        BufferType::Uchar => img.into_luma8().into_raw(),
+        BufferType::Uchar4 => img.into_rgba8().into_raw(),
+        BufferType::Float4 => img
+            .into_rgba8()
+            .into_raw()
+            .iter()
+            .flat_map(|&c| (c as f32 / 255.0).to_ne_bytes().to_vec())
+            .collect(),
+        _ => {
+            return Err(ClmanError::Storage(format!(
+                "Cannot load an image into a {:?} buffer",
+                buffer_type
+            )))
+        }
+    };
+    Ok((w, h, data))
+}
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+/// Shape of the npy array holding `count` elements of `buffer_type`. Vector
+/// components become the last axis.
+fn npy_shape(buffer_type: BufferType, count: usize, shape: Option<Vec<usize>>) -> Vec<usize> {
+    let mut shape = shape.unwrap_or_else(|| vec![count]);
+    if buffer_type.components() > 1 {
+        shape.push(buffer_type.components());
+    }
+    shape
+}
+
+/// Writes a buffer as a NumPy `.npy` (Version 1.0) file, dropping the padding
+/// of 3-component vectors.
+pub fn save_npy(
+    path: &str,
+    buffer_type: BufferType,
+    data: &[u8],
+    shape: Option<Vec<usize>>,
+) -> ClmanResult<()> {
+    let count = data.len() / buffer_type.size_of();
+    let shape = npy_shape(buffer_type, count, shape);
+    if shape.iter().product::<usize>() != count * buffer_type.components() {
+        return Err(ClmanError::Storage(format!(
+            "Shape {:?} does not match {} elements of {:?}",
+            shape, count, buffer_type
+        )));
+    }
+
+    let dims = shape.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': ({}{}), }}",
+        buffer_type.dtype(),
+        dims.join(", "),
+        if dims.len() == 1 { "," } else { "" }
+    );
+    // Magic, version and header length take 10 bytes, the header is padded
+    // with spaces and a newline to a multiple of 64 bytes
+    while (10 + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    let mut out = NPY_MAGIC.to_vec();
+    out.extend_from_slice(&[1, 0]);
+    out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    out.extend_from_slice(header.as_bytes());
+    let packed = buffer_type.scalar().size_of() * buffer_type.components();
+    for elem in data.chunks(buffer_type.size_of()) {
+        out.extend_from_slice(&elem[..packed]);
+    }
+    fs::write(path, out)?;
+    Ok(())
+}
+
+/// Reads a NumPy `.npy` file into the memory layout of a buffer, checking its
+/// dtype, and its shape if given.
+pub fn load_npy(
+    path: &str,
+    buffer_type: BufferType,
+    shape: Option<Vec<usize>>,
+) -> ClmanResult<Vec<u8>> {
+    let bytes = fs::read(path)?;
+    let invalid = || ClmanError::Storage(format!("{} is not a valid npy file", path));
+    if bytes.len() < 10 || &bytes[..6] != NPY_MAGIC {
+        return Err(invalid());
+    }
+    let (header_len, offset) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        _ if bytes.len() >= 12 => (
+            u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+            12,
+        ),
+        _ => return Err(invalid()),
+    };
+    let header = std::str::from_utf8(bytes.get(offset..offset + header_len).ok_or_else(invalid)?)
+        .map_err(|_| invalid())?;
+    let field = |name: &str, pattern: &str| {
+        Regex::new(&format!(r"'{}':\s*{}", name, pattern))
+            .unwrap()
+            .captures(header)
+            .map(|cap| cap[1].to_string())
+            .ok_or_else(invalid)
+    };
+
+    let descr = field("descr", r"'([^']*)'")?;
+    let expected = buffer_type.dtype();
+    let native = matches!(descr.chars().next(), Some('=') | Some('|'));
+    if descr != expected && !(native && descr[1..] == expected[1..]) {
+        return Err(ClmanError::Storage(format!(
+            "{} has dtype {}, expected {}",
+            path, descr, expected
+        )));
+    }
+    if field("fortran_order", r"(True|False)")? == "True" {
+        return Err(ClmanError::Storage(format!("{} is in Fortran order", path)));
+    }
+    let dims = field("shape", r"\(([^)]*)\)")?
+        .split(',')
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>().map_err(|_| invalid()))
+        .collect::<ClmanResult<Vec<_>>>()?;
+
+    let data = &bytes[offset + header_len..];
+    let packed = buffer_type.scalar().size_of() * buffer_type.components();
+    let count = data.len() / packed;
+    if let Some(shape) = shape {
+        let shape = npy_shape(buffer_type, count, Some(shape));
+        if dims != shape {
+            return Err(ClmanError::Storage(format!(
+                "{} has shape {:?}, expected {:?}",
+                path, dims, shape
+            )));
+        }
+    }
+    if dims.iter().product::<usize>() * buffer_type.scalar().size_of() != data.len() {
+        return Err(invalid());
+    }
+
+    let mut out = Vec::new();
+    for elem in data.chunks(packed) {
+        out.extend_from_slice(elem);
+        out.resize(out.len() + buffer_type.size_of() - packed, 0);
+    }
+    Ok(out)
+}
+
+/// Writes one element per row, vector components as columns, to `path` or to
+/// stdout.
+pub fn save_csv(
+    path: Option<String>,
+    buffer_type: BufferType,
+    data: &[u8],
+    header: Option<&[String]>,
+    limit: Option<usize>,
+) -> ClmanResult<()> {
+    let mut out: Box<dyn Write> = match path {
+        Some(path) => Box::new(BufWriter::new(fs::File::create(path)?)),
+        None => Box::new(std::io::stdout()),
+    };
+    if let Some(header) = header {
+        writeln!(out, "{}", header.join(","))?;
+    }
+    let count = limit.unwrap_or(usize::MAX);
+    for elem in data.chunks(buffer_type.size_of()).take(count) {
+        writeln!(out, "{}", buffer_type.format_element(elem).join(","))?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Prints elements, vectors as tuples, `width` elements per row
+pub fn print_values(buffer_type: BufferType, data: &[u8], width: Option<usize>) {
+    let elems = data
+        .chunks(buffer_type.size_of())
+        .map(|elem| {
+            let comps = buffer_type.format_element(elem);
+            if comps.len() == 1 {
+                comps[0].clone()
+            } else {
+                format!("({})", comps.join(", "))
+            }
+        })
+        .collect::<Vec<_>>();
+    let pad = elems.iter().map(|e| e.len()).max().unwrap_or(0);
+    for row in elems.chunks(width.unwrap_or(1).max(1)) {
+        let row = row
+            .iter()
+            .map(|e| format!("{:>1$}", e, pad))
+            .collect::<Vec<_>>();
+        println!("{}", row.join(" "));
+    }
+}
+
+/// Prints the minimum, maximum and mean of each component
+pub fn print_summary(buffer_type: BufferType, data: &[u8]) {
+    let comps = buffer_type.components();
+    let values = buffer_type.unpack(data);
+    println!("count: {}", values.len() / comps);
+    for c in 0..comps {
+        let column = values
+            .iter()
+            .skip(c)
+            .step_by(comps)
+            .cloned()
+            .collect::<Vec<_>>();
+        if column.is_empty() {
+            break;
+        }
+        let min = column.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = column.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let mean = column.iter().sum::<f64>() / column.len() as f64;
+        let label = if comps == 1 {
+            String::new()
+        } else {
+            format!("[{}] ", c)
+        };
+        println!("{}min: {}, max: {}, mean: {}", label, min, max, mean);
+    }
+}
+
+/// Reads a csv written by `save_csv` into the memory layout of a buffer.
+pub fn load_csv(path: &str, buffer_type: BufferType, header: bool) -> ClmanResult<Vec<u8>> {
+    let text = fs::read_to_string(path)?;
+    let values = text
+        .lines()
+        .skip(if header { 1 } else { 0 })
+        .flat_map(|line| line.split(','))
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            v.parse::<f64>().map_err(|_| {
+                ClmanError::Storage(format!("{} contains a non-numeric value: {}", path, v))
+            })
+        })
+        .collect::<ClmanResult<Vec<_>>>()?;
+    Ok(buffer_type.pack(&values))
+}
+
+/// RGB pixels of a `Float4` (Alpha is dropped), `Float3` or `Float`
+/// (Grayscale) buffer, without any clamping
+pub fn hdr_pixels(
+    w: usize,
+    h: usize,
+    buffer_type: BufferType,
+    data: &[u8],
+) -> ClmanResult<Vec<image::Rgb<f32>>> {
+    if data.len() != w * h * buffer_type.size_of() {
+        return Err(ClmanError::Storage(format!(
+            "Cannot save {} bytes of {:?} as a {}x{} image",
+            data.len(),
+            buffer_type,
+            w,
+            h
+        )));
+    }
+    let values = buffer_type.unpack(data);
+    Ok(match buffer_type {
+        BufferType::Float4 | BufferType::Float3 => values
+            .chunks(buffer_type.components())
+            .map(|c| image::Rgb([c[0] as f32, c[1] as f32, c[2] as f32]))
+            .collect(),
+        BufferType::Float => values
+            .into_iter()
+            .map(|v| image::Rgb([v as f32; 3]))
+            .collect(),
+        t => {
+            return Err(ClmanError::Storage(format!(
+                "Cannot save a {:?} buffer as an HDR image",
+                t
+            )))
+        }
+    })
+}
+
+fn is_pfm(path: &str) -> bool {
+    path.to_lowercase().ends_with(".pfm")
+}
+
+/// Writes full precision pixels as PFM or Radiance HDR, depending on the
+/// extension of `path`.
+pub fn save_hdr(path: &str, w: usize, h: usize, pixels: &[image::Rgb<f32>]) -> ClmanResult<()> {
+    let mut out = BufWriter::new(fs::File::create(path)?);
+    if is_pfm(path) {
+        // A negative scale means little-endian, rows are stored bottom to top
+        let scale = if cfg!(target_endian = "little") {
+            -1.0
+        } else {
+            1.0
+        };
+        write!(out, "PF\n{} {}\n{}\n", w, h, scale)?;
+        for row in pixels.chunks(w).rev() {
+            for pix in row {
+                for c in pix.0.iter() {
+                    out.write_all(&c.to_ne_bytes())?;
+                }
+            }
+        }
+    } else {
+        HdrEncoder::new(&mut out).encode(pixels, w, h)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn tonemap(tonemap: Option<Tonemap>, c: f64) -> f64 {
+    match tonemap {
+        Some(Tonemap::Reinhard) => c / (1.0 + c),
+        // Narkowicz's fit of the ACES filmic curve
+        Some(Tonemap::Aces) => (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14),
+        None => c,
+    }
+}
+
+/// Writes an LDR preview of HDR pixels, scaled by 2^`exposure`, tone mapped
+/// and gamma corrected.
+pub fn save_tonemapped(
+    path: &str,
+    w: usize,
+    h: usize,
+    pixels: &[image::Rgb<f32>],
+    tonemap_op: Option<Tonemap>,
+    exposure: f64,
+    gamma: f64,
+) -> ClmanResult<()> {
+    let scale = 2f64.powf(exposure);
+    let img = RgbImage::from_fn(w as u32, h as u32, |x, y| {
+        let pix = pixels[y as usize * w + x as usize];
+        let mut rgb = [0u8; 3];
+        for (out, &c) in rgb.iter_mut().zip(pix.0.iter()) {
+            let c = tonemap(tonemap_op, c as f64 * scale).clamp(0.0, 1.0);
+            *out = (c.powf(1.0 / gamma) * 255.0).round() as u8;
+        }
+        image::Rgb(rgb)
+    });
+    img.save(path)?;
+    Ok(())
+}
+
+/// Reads the header of a PFM file, returning its dimensions, channel count and
+/// scale, and leaves `reader` at the start of the pixel data
+fn read_pfm_header<R: BufRead>(reader: &mut R) -> ClmanResult<(usize, usize, usize, f64)> {
+    let mut tokens = Vec::new();
+    while tokens.len() < 4 {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        tokens.extend(line.split_whitespace().map(String::from));
+    }
+    let invalid = || ClmanError::Storage("Invalid PFM header".into());
+    if tokens.len() != 4 {
+        return Err(invalid());
+    }
+    let channels = match &tokens[0][..] {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid()),
+    };
+    let w = tokens[1].parse().map_err(|_| invalid())?;
+    let h = tokens[2].parse().map_err(|_| invalid())?;
+    let scale = tokens[3].parse().map_err(|_| invalid())?;
+    Ok((w, h, channels, scale))
+}
+
+pub fn hdr_dimensions(path: &str) -> ClmanResult<(usize, usize)> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    if is_pfm(path) {
+        let (w, h, _, _) = read_pfm_header(&mut reader)?;
+        Ok((w, h))
+    } else {
+        let meta = HdrDecoder::new(reader)?.metadata();
+        Ok((meta.width as usize, meta.height as usize))
+    }
+}
+
+/// Reads a PFM or Radiance HDR file into a `Float4` (Alpha set to 1),
+/// `Float3` or `Float` (Luminance) buffer.
+pub fn load_hdr(path: &str, buffer_type: BufferType) -> ClmanResult<(usize, usize, Vec<u8>)> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let (w, h, pixels) = if is_pfm(path) {
+        let (w, h, channels, scale) = read_pfm_header(&mut reader)?;
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let count = match w.checked_mul(h).and_then(|n| n.checked_mul(channels)) {
+            Some(count) if count > 0 => count,
+            _ => return Err(ClmanError::Storage(format!("{} is {}x{}", path, w, h))),
+        };
+        // Anything after the pixels, like a trailing newline, is ignored
+        let data = count
+            .checked_mul(4)
+            .and_then(|size| bytes.get(..size))
+            .ok_or_else(|| ClmanError::Storage(format!("{} is truncated", path)))?;
+        let values = data
+            .chunks_exact(4)
+            .map(|b| {
+                let b = [b[0], b[1], b[2], b[3]];
+                if scale < 0.0 {
+                    f32::from_le_bytes(b)
+                } else {
+                    f32::from_be_bytes(b)
+                }
+            })
+            .collect::<Vec<_>>();
+        let rows = values.chunks(w * channels).rev();
+        let pixels = rows
+            .flat_map(|row| row.chunks(channels))
+            .map(|c| image::Rgb([c[0], c[channels / 2], c[channels - 1]]))
+            .collect::<Vec<_>>();
+        (w, h, pixels)
+    } else {
+        let decoder = HdrDecoder::new(reader)?;
+        let meta = decoder.metadata();
+        let pixels = decoder.read_image_hdr()?;
+        (meta.width as usize, meta.height as usize, pixels)
+    };
+    let values = match buffer_type {
+        BufferType::Float4 => pixels
+            .iter()
+            .flat_map(|p| vec![p[0], p[1], p[2], 1.0])
+            .collect::<Vec<_>>(),
+        BufferType::Float3 => pixels.iter().flat_map(|p| p.0.to_vec()).collect(),
+        BufferType::Float => pixels
+            .iter()
+            .map(|p| 0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2])
+            .collect(),
+        t => {
+            return Err(ClmanError::Storage(format!(
+                "Cannot load an HDR image into a {:?} buffer",
+                t
+            )))
+        }
+    };
+    let values = values.into_iter().map(|v| v as f64).collect::<Vec<_>>();
+    Ok((w, h, buffer_type.pack(&values)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("clman-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn floats(values: &[f32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|v| v.to_ne_bytes().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn npy_header_is_padded() {
+        let path = temp_path("padded.npy");
+        save_npy(&path, BufferType::Float, &floats(&[1.0, 2.0, 3.0]), None).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&bytes[10..10 + header_len]).unwrap();
+        assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (3,), }"));
+        assert!(header.ends_with(" \n"));
+        assert_eq!(bytes.len(), 10 + header_len + 12);
+    }
+
+    #[test]
+    fn npy_round_trip_restores_vector_padding() {
+        let path = temp_path("float3.npy");
+        // Two float3 elements, each padded to the size of a float4
+        let data = floats(&[1.0, 2.0, 3.0, 0.0, 4.0, 5.0, 6.0, 0.0]);
+        save_npy(&path, BufferType::Float3, &data, None).unwrap();
+        let loaded = load_npy(&path, BufferType::Float3, Some(vec![2]));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), data);
+    }
+
+    #[test]
+    fn npy_loads_version_2_headers() {
+        let mut header = "{'descr': '<f4', 'fortran_order': False, 'shape': (2,), }".to_string();
+        while !(12 + header.len() + 1).is_multiple_of(64) {
+            header.push(' ');
+        }
+        header.push('\n');
+        let mut bytes = b"\x93NUMPY\x02\x00".to_vec();
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend(floats(&[1.5, -2.5]));
+
+        let path = temp_path("v2.npy");
+        fs::write(&path, bytes).unwrap();
+        let loaded = load_npy(&path, BufferType::Float, None);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), floats(&[1.5, -2.5]));
+    }
+
+    #[test]
+    fn npy_rejects_other_dtypes_and_shapes() {
+        let path = temp_path("dtype.npy");
+        save_npy(&path, BufferType::Float, &floats(&[1.0, 2.0]), None).unwrap();
+        let dtype = load_npy(&path, BufferType::Int, None);
+        let shape = load_npy(&path, BufferType::Float, Some(vec![3]));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(dtype, Err(ClmanError::Storage(_))));
+        assert!(matches!(shape, Err(ClmanError::Storage(_))));
+    }
+
+    #[test]
+    fn pfm_ignores_trailing_bytes() {
+        let path = temp_path("trailing.pfm");
+        save_hdr(&path, 1, 1, &[image::Rgb([0.25, 0.5, 1.0])]).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.push(b'\n');
+        fs::write(&path, bytes).unwrap();
+        let loaded = load_hdr(&path, BufferType::Float3);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), (1, 1, floats(&[0.25, 0.5, 1.0, 0.0])));
+    }
+
+    #[test]
+    fn pfm_rejects_truncated_pixels() {
+        let path = temp_path("truncated.pfm");
+        fs::write(&path, b"PF\n2 1\n-1.0\n\0\0\0\0").unwrap();
+        let loaded = load_hdr(&path, BufferType::Float3);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(loaded, Err(ClmanError::Storage(_))));
+    }
+}
@@ -0,0 +1,338 @@
+use crate::cl;
+use crate::conf::{
+    Arg, BufferType, Case, Computable, Device, Environment, Pattern, Tolerance, ValueString,
+};
+use crate::error::{ClmanError, ClmanResult};
+use crate::parse;
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+
+/// Outcome of comparing a buffer against its expected contents
+pub struct Comparison {
+    pub max_error: f64,
+    /// Element and component of the first value out of tolerance
+    pub mismatch: Option<(usize, usize)>,
+}
+
+/// Distance between two values in units in the last place, for float types
+fn ulps(scalar: BufferType, a: f64, b: f64) -> Option<u64> {
+    // Maps the bits of a float to an integer that is ordered like the floats
+    let ordered = |bits: i128, min: i128| if bits < 0 { min - bits } else { bits };
+    match scalar {
+        BufferType::Float => {
+            let key = |v: f64| ordered((v as f32).to_bits() as i32 as i128, i32::MIN as i128);
+            Some((key(a) - key(b)).unsigned_abs() as u64)
+        }
+        BufferType::Double => {
+            let key = |v: f64| ordered(v.to_bits() as i64 as i128, i64::MIN as i128);
+            Some((key(a) - key(b)).unsigned_abs().min(u64::MAX as u128) as u64)
+        }
+        _ => None,
+    }
+}
+
+fn matches(scalar: BufferType, tolerance: &Tolerance, expected: f64, actual: f64) -> bool {
+    if expected == actual || (expected.is_nan() && actual.is_nan()) {
+        return true;
+    }
+    let error = (expected - actual).abs();
+    tolerance.abs.is_some_and(|abs| error <= abs)
+        || tolerance
+            .rel
+            .is_some_and(|rel| error <= rel * expected.abs())
+        || match (tolerance.ulp, ulps(scalar, expected, actual)) {
+            (Some(ulp), Some(distance)) => distance <= ulp,
+            _ => false,
+        }
+}
+
+/// Compares the contents of buffer `name` with what `tolerance` allows
+pub fn compare(
+    name: &str,
+    buffer_type: BufferType,
+    tolerance: &Tolerance,
+    expected: &[u8],
+    actual: &[u8],
+) -> ClmanResult<Comparison> {
+    if expected.len() != actual.len() {
+        return Err(ClmanError::Size {
+            name: name.to_string(),
+            expected: actual.len(),
+            actual: expected.len(),
+        });
+    }
+    let scalar = buffer_type.scalar();
+    let comps = buffer_type.components();
+    let mut comparison = Comparison {
+        max_error: 0.0,
+        mismatch: None,
+    };
+    for (i, (&e, &a)) in buffer_type
+        .unpack(expected)
+        .iter()
+        .zip(buffer_type.unpack(actual).iter())
+        .enumerate()
+    {
+        let error = match (e - a).abs() {
+            _ if e == a || (e.is_nan() && a.is_nan()) => 0.0,
+            error if error.is_nan() => f64::INFINITY,
+            error => error,
+        };
+        comparison.max_error = comparison.max_error.max(error);
+        if comparison.mismatch.is_none() && !matches(scalar, tolerance, e, a) {
+            comparison.mismatch = Some((i / comps, i % comps));
+        }
+    }
+    Ok(comparison)
+}
+
+/// Buffer type named by an OpenCL type, ignoring qualifiers
+fn buffer_type(function: &str, decl: &str) -> ClmanResult<(String, BufferType)> {
+    let unsupported = || {
+        ClmanError::Test(format!(
+            "Cannot test {}, type {:?} is not supported",
+            function, decl
+        ))
+    };
+    if decl.contains('*') || decl.contains('[') {
+        return Err(unsupported());
+    }
+    let words = decl
+        .split_whitespace()
+        .filter(|w| !["const", "volatile", "__private", "private"].contains(w))
+        .collect::<Vec<_>>();
+    let name = match words[..] {
+        ["unsigned", t] => format!("u{}", t),
+        [t] => t.to_string(),
+        _ => return Err(unsupported()),
+    };
+    let buffer_type = BufferType::deserialize(name.as_str().into_deserializer())
+        .map_err(|_: serde::de::value::Error| unsupported())?;
+    Ok((name, buffer_type))
+}
+
+/// Types of an OpenCL function that takes and returns values, which can be
+/// called for every element of a set of buffers
+pub struct Signature {
+    pub name: String,
+    pub returns: (String, BufferType),
+    pub params: Vec<(String, BufferType)>,
+}
+
+impl Signature {
+    pub fn new(function: &parse::Function) -> ClmanResult<Self> {
+        let params = function.params.trim();
+        Ok(Self {
+            name: function.name.clone(),
+            returns: buffer_type(&function.name, &function.returns)?,
+            params: if params.is_empty() || params == "void" {
+                Vec::new()
+            } else {
+                params
+                    .split(',')
+                    .map(|param| {
+                        // Drop the name of the parameter
+                        let mut words = param.split_whitespace().collect::<Vec<_>>();
+                        words.pop();
+                        buffer_type(&function.name, &words.join(" "))
+                    })
+                    .collect::<ClmanResult<_>>()?
+            },
+        })
+    }
+
+    pub fn kernel_name(&self) -> String {
+        format!("clman_test_{}", self.name)
+    }
+
+    /// Kernel calling the function with the i-th element of every input
+    /// buffer, and storing the result in the i-th element of the output buffer
+    pub fn wrapper(&self) -> String {
+        let mut params = self
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, (t, _))| format!("__global const {} *in{}", t, i))
+            .collect::<Vec<_>>();
+        params.push(format!("__global {} *out", self.returns.0));
+        let args = (0..self.params.len())
+            .map(|i| format!("in{}[i]", i))
+            .collect::<Vec<_>>();
+        format!(
+            "\n__kernel void {}({}) {{\n  size_t i = get_global_id(0);\n  out[i] = {}({});\n}}\n",
+            self.kernel_name(),
+            params.join(", "),
+            self.name,
+            args.join(", ")
+        )
+    }
+}
+
+/// Calls `function` of `src` once for each case. Returns the type of the
+/// results, followed by the expected and the actual results.
+pub fn run_function(
+    env: &Environment,
+    src: String,
+    build_options: String,
+    device: Option<&Device>,
+    function: &str,
+    cases: &[Case],
+) -> ClmanResult<(BufferType, Vec<u8>, Vec<u8>)> {
+    let signature = parse::list_functions(src.clone())
+        .iter()
+        .find(|f| f.name == function)
+        .map(Signature::new)
+        .unwrap_or_else(|| Err(ClmanError::Test(format!("No function named {}", function))))?;
+
+    if cases.is_empty() {
+        return Err(ClmanError::Test(format!("No cases for {}", function)));
+    }
+    for (i, case) in cases.iter().enumerate() {
+        if case.args.len() != signature.params.len() {
+            return Err(ClmanError::Test(format!(
+                "Case {} of {} has {} arguments, {} takes {}",
+                i,
+                function,
+                case.args.len(),
+                function,
+                signature.params.len()
+            )));
+        }
+    }
+
+    // Broadcasts scalars, like fill jobs do
+    let pack = |i: usize, value: &Pattern, buffer_type: BufferType| {
+        let mut value = value.compute(env);
+        if value.len() == 1 {
+            value = vec![value[0]; buffer_type.components()];
+        }
+        if value.len() != buffer_type.components() {
+            return Err(ClmanError::Test(format!(
+                "Case {} of {} has {} values where {:?} is expected",
+                i,
+                function,
+                value.len(),
+                buffer_type
+            )));
+        }
+        Ok(buffer_type.pack(&value))
+    };
+
+    let mut gpu = cl::GPU::new(src + &signature.wrapper(), build_options, device, false)?;
+    let mut args = Vec::new();
+    for (p, (_, buffer_type)) in signature.params.iter().enumerate() {
+        let mut data = Vec::new();
+        for (i, case) in cases.iter().enumerate() {
+            data.extend(pack(i, &case.args[p], *buffer_type)?);
+        }
+        let name = format!("in{}", p);
+        gpu.create_buffer(name.clone(), *buffer_type, cases.len())?;
+        gpu.write_buffer(name.clone(), &data)?;
+        args.push(Arg::Buffer(ValueString(name)));
+    }
+
+    let returns = signature.returns.1;
+    let mut expected = Vec::new();
+    for (i, case) in cases.iter().enumerate() {
+        expected.extend(pack(i, &case.returns, returns)?);
+    }
+    gpu.create_buffer("out".into(), returns, cases.len())?;
+    args.push(Arg::Buffer(ValueString("out".into())));
+
+    gpu.run_kernel(
+        env,
+        signature.kernel_name(),
+        args,
+        cl::NDRange {
+            global: vec![cases.len()],
+            local: None,
+            offset: None,
+        },
+    )?;
+    Ok((returns, expected, gpu.read_buffer::<u8>("out".into())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tolerance(abs: Option<f64>, rel: Option<f64>, ulp: Option<u64>) -> Tolerance {
+        Tolerance { abs, rel, ulp }
+    }
+
+    #[test]
+    fn ulps_across_zero() {
+        let tiny = f32::from_bits(1) as f64;
+        assert_eq!(ulps(BufferType::Float, -0.0, 0.0), Some(0));
+        assert_eq!(ulps(BufferType::Float, -0.0, tiny), Some(1));
+        assert_eq!(ulps(BufferType::Float, 0.0, -tiny), Some(1));
+        assert_eq!(ulps(BufferType::Float, -tiny, tiny), Some(2));
+        assert_eq!(ulps(BufferType::Float, -1.0, -1.0), Some(0));
+        assert_eq!(
+            ulps(BufferType::Float, -1.0, -(1.0 + f32::EPSILON as f64)),
+            Some(1)
+        );
+        assert_eq!(
+            ulps(BufferType::Double, f64::from_bits(1), -f64::from_bits(1)),
+            Some(2)
+        );
+        assert_eq!(ulps(BufferType::Int, 1.0, 2.0), None);
+    }
+
+    #[test]
+    fn signed_zeros_and_nans_match_exactly() {
+        let exact = tolerance(None, None, None);
+        assert!(matches(BufferType::Float, &exact, 0.0, -0.0));
+        assert!(matches(BufferType::Float, &exact, f64::NAN, f64::NAN));
+        assert!(!matches(BufferType::Float, &exact, f64::NAN, 0.0));
+        assert!(!matches(BufferType::Float, &exact, 1.0, 1.5));
+    }
+
+    #[test]
+    fn tolerances_combine_with_or() {
+        let abs = tolerance(Some(0.1), None, None);
+        let abs_rel = tolerance(Some(0.1), Some(0.01), None);
+        assert!(!matches(BufferType::Float, &abs, 1000.0, 1001.0));
+        assert!(matches(BufferType::Float, &abs_rel, 1000.0, 1001.0));
+        assert!(matches(BufferType::Float, &abs_rel, 0.0, 0.05));
+        assert!(!matches(BufferType::Float, &abs_rel, 1000.0, 1020.0));
+
+        let next = f32::from_bits(1.0f32.to_bits() + 1) as f64;
+        let ulp = tolerance(Some(0.0), None, Some(1));
+        assert!(matches(BufferType::Float, &ulp, 1.0, next));
+        assert!(!matches(
+            BufferType::Float,
+            &ulp,
+            1.0,
+            next + f32::EPSILON as f64
+        ));
+        // Ulps only apply to floats
+        assert!(!matches(
+            BufferType::Int,
+            &tolerance(None, None, Some(1)),
+            1.0,
+            2.0
+        ));
+    }
+
+    #[test]
+    fn compare_reports_first_mismatch() {
+        let expected = BufferType::Float2.pack(&[0.0, f64::NAN, 1.0, 2.0]);
+        let actual = BufferType::Float2.pack(&[-0.0, f64::NAN, 1.0, 2.5]);
+        let exact = tolerance(None, None, None);
+        let comparison = compare("out", BufferType::Float2, &exact, &expected, &actual).unwrap();
+        assert_eq!(comparison.max_error, 0.5);
+        assert_eq!(comparison.mismatch, Some((1, 1)));
+
+        let actual = BufferType::Float2.pack(&[0.0, 1.0, 1.0, 2.0]);
+        let comparison = compare("out", BufferType::Float2, &exact, &expected, &actual).unwrap();
+        assert_eq!(comparison.max_error, f64::INFINITY);
+        assert_eq!(comparison.mismatch, Some((0, 1)));
+
+        let short = BufferType::Float2.pack(&[0.0, 0.0]);
+        assert!(matches!(
+            compare("out", BufferType::Float2, &exact, &expected, &short),
+            Err(ClmanError::Size { .. })
+        ));
+    }
+}